@@ -0,0 +1,496 @@
+use cortex_m::interrupt;
+use cortex_m::peripheral::NVIC;
+use stm32f103xx::Interrupt;
+use nb;
+use gpio::*;
+use timing::timer::{self, TimerId, Channel, SlaveMode};
+
+#[derive(Debug)]
+pub enum CaptureError {
+    InvalidFrequency,
+    ConfigError,
+    // a capture was overwritten before being read
+    Overcapture,
+}
+
+type CaptureResult<T> = Result<T, CaptureError>;
+
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum Edge {
+    Rising,
+    Falling,
+}
+
+// captures once every n edges, ICxPSC
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum CapturePrescaler {
+    Div1 = 0b00,
+    Div2 = 0b01,
+    Div4 = 0b10,
+    Div8 = 0b11,
+}
+
+pub struct InputConfig {
+    edge : Edge,
+    filter : u8,
+    prescaler : CapturePrescaler,
+}
+
+impl InputConfig {
+    pub fn new() -> InputConfig {
+        InputConfig {
+            edge : Edge::Rising,
+            filter : 0,
+            prescaler : CapturePrescaler::Div1,
+        }
+    }
+
+    pub fn edge(mut self, edge : Edge) -> InputConfig {
+        self.edge = edge;
+        self
+    }
+
+    // ICxF code from 0 (no filter) to 15, see the reference manual for the
+    // sampling frequency and the number of samples of each code
+    pub fn filter(mut self, filter : u8) -> InputConfig {
+        self.filter = filter;
+        self
+    }
+
+    pub fn prescaler(mut self, psc : CapturePrescaler) -> InputConfig {
+        self.prescaler = psc;
+        self
+    }
+}
+
+const SR_CC1IF : u32 = 1 << 1;
+const SR_CC1OF : u32 = 1 << 9;
+const DIER_UIE : u32 = 1 << 0;
+const DIER_CC1IE : u32 = 1 << 1;
+const CR1_URS : u32 = 1 << 2;
+
+// TS codes of the filtered timer inputs
+const TS_TI1FP1 : u32 = 0b101;
+const TS_TI2FP2 : u32 = 0b110;
+
+// ticks counted by the interrupt handler, the 16 bits counter is extended
+// with the number of update events
+#[derive(Copy, Clone)]
+struct State {
+    mode : StateMode,
+    overflows : u32,
+    // extended timestamp of the last capture of each channel
+    last : [u32; 4],
+    // ticks between the last two captures, 0 until there were two
+    period : [u32; 4],
+    fresh : [bool; 4],
+    overcapture : [bool; 4],
+    seen : [bool; 4],
+    // pwm input, overflows are counted from the last period start
+    width : u32,
+    pwm_fresh : bool,
+}
+
+#[derive(Eq, PartialEq, Copy, Clone)]
+enum StateMode {
+    Off,
+    Capture,
+    // channel on which the period is measured
+    PwmInput(Channel),
+}
+
+const IDLE_STATE : State = State {
+    mode : StateMode::Off,
+    overflows : 0,
+    last : [0; 4],
+    period : [0; 4],
+    fresh : [false; 4],
+    overcapture : [false; 4],
+    seen : [false; 4],
+    width : 0,
+    pwm_fresh : false,
+};
+
+static mut STATES : [State; 4] = [IDLE_STATE; 4];
+
+fn index(id : TimerId) -> usize {
+    match id {
+        TimerId::Tim1 => 0,
+        TimerId::Tim2 => 1,
+        TimerId::Tim3 => 2,
+        TimerId::Tim4 => 3,
+    }
+}
+
+pub struct CaptureConfig {
+    timer : TimerId,
+    tick_frequency : u32,
+    channels : [Option<InputConfig>; 4],
+}
+
+impl CaptureConfig {
+    pub fn new(timer : TimerId) -> CaptureConfig {
+        CaptureConfig {
+            timer,
+            tick_frequency : 1_000_000,
+            channels : [None, None, None, None],
+        }
+    }
+
+    // counter frequency, the captures are given in these ticks
+    pub fn tick_frequency(mut self, hz : u32) -> CaptureConfig {
+        self.tick_frequency = hz;
+        self
+    }
+
+    pub fn channel(mut self, ch : Channel, input : InputConfig) -> CaptureConfig {
+        self.channels[ch.index()] = Some(input);
+        self
+    }
+
+    // the counter runs freely over 0xFFFF, captures are reported as 32 bits timestamps
+    pub fn configure(&self) -> CaptureResult<Capture> {
+        let tick_frequency = start_counter(self.timer, self.tick_frequency)?;
+        let all = [Channel::Ch1, Channel::Ch2, Channel::Ch3, Channel::Ch4];
+        let mut dier = DIER_UIE;
+        for &ch in all.iter() {
+            if let Some(ref input) = self.channels[ch.index()] {
+                configure_input(self.timer, ch, input, ch)?;
+                dier |= DIER_CC1IE << ch.index();
+            }
+        }
+        interrupt::free(|_| unsafe {
+            STATES[index(self.timer)] = IDLE_STATE;
+            STATES[index(self.timer)].mode = StateMode::Capture;
+        });
+        enable_interrupts(self.timer, dier);
+        Ok(Capture {
+            timer : self.timer,
+            tick_frequency,
+        })
+    }
+}
+
+pub struct Capture {
+    timer : TimerId,
+    tick_frequency : u32,
+}
+
+impl Capture {
+    pub fn tick_frequency(&self) -> u32 {
+        self.tick_frequency
+    }
+
+    // timestamp of the last edge, in ticks. WouldBlock until a new edge is
+    // captured, Overcapture when edges were missed since the last read
+    pub fn read(&mut self, ch : Channel) -> nb::Result<u32, CaptureError> {
+        let idx = index(self.timer);
+        interrupt::free(|_| unsafe {
+            let state = &mut STATES[idx];
+            if !state.fresh[ch.index()] {
+                return Err(nb::Error::WouldBlock);
+            }
+            state.fresh[ch.index()] = false;
+            if state.overcapture[ch.index()] {
+                state.overcapture[ch.index()] = false;
+                return Err(nb::Error::Other(CaptureError::Overcapture));
+            }
+            Ok(state.last[ch.index()])
+        })
+    }
+
+    // ticks between the last two edges
+    pub fn period(&self, ch : Channel) -> Option<u32> {
+        let idx = index(self.timer);
+        let period = interrupt::free(|_| unsafe { STATES[idx].period[ch.index()] });
+        if period == 0 { None } else { Some(period) }
+    }
+
+    // in millihertz to keep some precision on slow signals
+    pub fn frequency_millihz(&self, ch : Channel) -> Option<u32> {
+        self.period(ch).map(|p| (self.tick_frequency as u64 * 1000 / p as u64) as u32)
+    }
+
+    pub fn release(self) -> TimerId {
+        stop(self.timer);
+        self.timer
+    }
+}
+
+pub struct PwmInputConfig {
+    timer : TimerId,
+    channel : Channel,
+    tick_frequency : u32,
+    filter : u8,
+}
+
+impl PwmInputConfig {
+    // the signal is on ch1 or ch2, it uses both capture channels
+    pub fn new(timer : TimerId, channel : Channel) -> PwmInputConfig {
+        PwmInputConfig {
+            timer,
+            channel,
+            tick_frequency : 1_000_000,
+            filter : 0,
+        }
+    }
+
+    pub fn tick_frequency(mut self, hz : u32) -> PwmInputConfig {
+        self.tick_frequency = hz;
+        self
+    }
+
+    pub fn filter(mut self, filter : u8) -> PwmInputConfig {
+        self.filter = filter;
+        self
+    }
+
+    // the rising edges capture the period and reset the counter, the falling
+    // edges capture the high time on the other channel
+    pub fn configure(&self) -> CaptureResult<PwmInput> {
+        let (other, ts) = match self.channel {
+            Channel::Ch1 => (Channel::Ch2, TS_TI1FP1),
+            Channel::Ch2 => (Channel::Ch1, TS_TI2FP2),
+            _ => return Err(CaptureError::ConfigError),
+        };
+        let tick_frequency = start_counter(self.timer, self.tick_frequency)?;
+        let rising = InputConfig::new().filter(self.filter);
+        let falling = InputConfig::new().filter(self.filter).edge(Edge::Falling);
+        configure_input(self.timer, self.channel, &rising, self.channel)?;
+        configure_input(self.timer, other, &falling, self.channel)?;
+        timer::set_slave_mode(self.timer, SlaveMode::Reset, ts);
+        unsafe {
+            // only the overflows set the update flag, not the resets
+            timer::regs(self.timer).cr1.modify(|r, w| w.bits(r.bits() | CR1_URS));
+        }
+        interrupt::free(|_| unsafe {
+            STATES[index(self.timer)] = IDLE_STATE;
+            STATES[index(self.timer)].mode = StateMode::PwmInput(self.channel);
+        });
+        let dier = DIER_UIE | DIER_CC1IE << self.channel.index() | DIER_CC1IE << other.index();
+        enable_interrupts(self.timer, dier);
+        Ok(PwmInput {
+            timer : self.timer,
+            channel : self.channel,
+            tick_frequency,
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct PwmMeasure {
+    // in ticks
+    pub period : u32,
+    pub high : u32,
+}
+
+pub struct PwmInput {
+    timer : TimerId,
+    channel : Channel,
+    tick_frequency : u32,
+}
+
+impl PwmInput {
+    pub fn tick_frequency(&self) -> u32 {
+        self.tick_frequency
+    }
+
+    // last complete period, WouldBlock until a new one was measured
+    pub fn read(&mut self) -> nb::Result<PwmMeasure, CaptureError> {
+        let idx = index(self.timer);
+        let ch = self.channel.index();
+        interrupt::free(|_| unsafe {
+            let state = &mut STATES[idx];
+            if !state.pwm_fresh {
+                return Err(nb::Error::WouldBlock);
+            }
+            state.pwm_fresh = false;
+            Ok(PwmMeasure {
+                period : state.period[ch],
+                high : state.width,
+            })
+        })
+    }
+
+    // duty in per mille of the last period
+    pub fn duty(&self) -> Option<u32> {
+        let idx = index(self.timer);
+        let ch = self.channel.index();
+        let (period, high) = interrupt::free(|_| unsafe { (STATES[idx].period[ch], STATES[idx].width) });
+        if period == 0 { None } else { Some((high as u64 * 1000 / period as u64) as u32) }
+    }
+
+    // true when no edge came for more than limit ticks, the last measure is stale
+    pub fn is_stalled(&self, limit : u32) -> bool {
+        let idx = index(self.timer);
+        let overflows = interrupt::free(|_| unsafe { STATES[idx].overflows });
+        overflows as u64 * 0x1_0000 > limit as u64
+    }
+
+    pub fn release(self) -> TimerId {
+        timer::set_slave_mode(self.timer, SlaveMode::Disabled, 0);
+        stop(self.timer);
+        self.timer
+    }
+}
+
+// psc for the requested tick frequency, arr at its maximum for the overflow counting
+fn start_counter(id : TimerId, hz : u32) -> CaptureResult<u32> {
+    let clk = timer::clock(id);
+    if hz == 0 || hz > clk || clk / hz > 0x1_0000 {
+        return Err(CaptureError::InvalidFrequency);
+    }
+    let psc = clk / hz - 1;
+    timer::enable_clock(id);
+    let tim = timer::regs(id);
+    unsafe {
+        tim.cr1.write(|w| w.bits(0));
+        tim.dier.write(|w| w.bits(0));
+        tim.ccer.write(|w| w.bits(0));
+        tim.psc.write(|w| w.bits(psc));
+        tim.arr.write(|w| w.bits(0xFFFF));
+        tim.egr.write(|w| w.ug().bit(true));
+        tim.sr.write(|w| w.bits(0));
+        tim.cr1.modify(|_, w| w.cen().bit(true));
+    }
+    Ok(clk / (psc + 1))
+}
+
+// input is the TIx on which the channel captures, its own or the one of
+// its pair (ch1/ch2 and ch3/ch4)
+fn configure_input(id : TimerId, ch : Channel, cfg : &InputConfig, input : Channel) -> CaptureResult<()> {
+    if cfg.filter > 15 {
+        return Err(CaptureError::ConfigError);
+    }
+    let (port, pin) = timer::channel_pin(id, input);
+    GpioConfig::new()
+        .port(port)
+        .pin(pin)
+        .conf(Conf::FloatingIn)
+        .mode(Mode::Input)
+        .configure()
+        .map_err(|_| CaptureError::ConfigError)?;
+
+    // CCxS 01 maps the channel to its own input, 10 to the other input of the pair
+    let ccs = if input == ch { 0b01 } else { 0b10 };
+    let shift = (ch.index() % 2) * 8;
+    let field = ((cfg.filter as u32) << 4 | (cfg.prescaler as u32) << 2 | ccs) << shift;
+    let mask = 0xFF << shift;
+    let tim = timer::regs(id);
+    let enable = 1 << (ch.index() * 4);
+    let falling = 1 << (ch.index() * 4 + 1);
+    unsafe {
+        // CCxS is only writable with the channel off
+        tim.ccer.modify(|r, w| w.bits(r.bits() & !(enable | falling)));
+        match ch {
+            Channel::Ch1 | Channel::Ch2 => tim.ccmr1_input.modify(|r, w| w.bits(r.bits() & !mask | field)),
+            Channel::Ch3 | Channel::Ch4 => tim.ccmr2_input.modify(|r, w| w.bits(r.bits() & !mask | field)),
+        }
+        let pol = if cfg.edge == Edge::Falling { falling } else { 0 };
+        tim.ccer.modify(|r, w| w.bits(r.bits() | pol | enable));
+    }
+    Ok(())
+}
+
+fn enable_interrupts(id : TimerId, dier : u32) {
+    unsafe {
+        timer::regs(id).dier.write(|w| w.bits(dier));
+        let nvic = &*NVIC.get();
+        match id {
+            TimerId::Tim1 => {
+                nvic.enable(Interrupt::TIM1_UP);
+                nvic.enable(Interrupt::TIM1_CC);
+            },
+            TimerId::Tim2 => nvic.enable(Interrupt::TIM2),
+            TimerId::Tim3 => nvic.enable(Interrupt::TIM3),
+            TimerId::Tim4 => nvic.enable(Interrupt::TIM4),
+        }
+    }
+}
+
+fn stop(id : TimerId) {
+    let tim = timer::regs(id);
+    unsafe {
+        tim.cr1.modify(|_, w| w.cen().bit(false));
+        tim.dier.write(|w| w.bits(0));
+        tim.ccer.write(|w| w.bits(0));
+    }
+    interrupt::free(|_| unsafe { STATES[index(id)] = IDLE_STATE });
+}
+
+fn ccr(id : TimerId, ch : Channel) -> u32 {
+    timer::compare(id, ch) as u32
+}
+
+// called from the timer interrupts, before the update flag is cleared
+pub fn handler(id : TimerId) {
+    let state = unsafe { &mut STATES[index(id)] };
+    if state.mode == StateMode::Off {
+        return;
+    }
+    let sr = timer::regs(id).sr.read().bits();
+    let overflow = sr & timer::SR_UIF != 0;
+    let all = [Channel::Ch1, Channel::Ch2, Channel::Ch3, Channel::Ch4];
+
+    match state.mode {
+        StateMode::Capture => {
+            for &ch in all.iter() {
+                let i = ch.index();
+                if sr & (SR_CC1IF << i) == 0 {
+                    continue;
+                }
+                // reading ccr clears CCxIF
+                let value = ccr(id, ch);
+                // with an overflow pending, a small capture happened after it
+                let high = if overflow && value < 0x8000 {
+                    state.overflows.wrapping_add(1)
+                } else {
+                    state.overflows
+                };
+                let ts = high << 16 | value;
+                if state.fresh[i] || sr & (SR_CC1OF << i) != 0 {
+                    state.overcapture[i] = true;
+                    timer::clear_flags(id, SR_CC1OF << i);
+                }
+                if state.seen[i] {
+                    state.period[i] = ts.wrapping_sub(state.last[i]);
+                }
+                state.last[i] = ts;
+                state.fresh[i] = true;
+                state.seen[i] = true;
+            }
+            if overflow {
+                state.overflows = state.overflows.wrapping_add(1);
+            }
+        },
+        StateMode::PwmInput(period_ch) => {
+            let width_ch = if period_ch == Channel::Ch1 { Channel::Ch2 } else { Channel::Ch1 };
+            let period_flag = sr & (SR_CC1IF << period_ch.index()) != 0;
+            let width_flag = sr & (SR_CC1IF << width_ch.index()) != 0;
+            if width_flag {
+                let value = ccr(id, width_ch);
+                // with an overflow pending, a small capture happened after it
+                let ovf = if overflow && value < 0x8000 {
+                    state.overflows.saturating_add(1)
+                } else {
+                    state.overflows
+                };
+                state.width = ovf.saturating_mul(0x1_0000).saturating_add(value);
+            }
+            if overflow {
+                state.overflows = state.overflows.saturating_add(1);
+            }
+            // the counter restarts at each period, so a pending overflow
+            // happened before the rising edge
+            if period_flag {
+                let value = ccr(id, period_ch);
+                let ovf = state.overflows;
+                state.period[period_ch.index()] = ovf.saturating_mul(0x1_0000).saturating_add(value);
+                state.overflows = 0;
+                state.pwm_fresh = true;
+            }
+            timer::clear_flags(id, (SR_CC1OF << period_ch.index()) | (SR_CC1OF << width_ch.index()));
+        },
+        StateMode::Off => {},
+    }
+}
@@ -1,4 +1,5 @@
 use stm32f103xx::RCC;
+use core::fmt;
 
 #[derive(Debug)]
 pub enum ClockError {
@@ -127,6 +128,21 @@ pub struct ClockSpeeds {
     pub ahb_clk : u32,
     pub apb1_clk : u32,
     pub apb2_clk : u32,
+    // timers clocks, twice the apb clock when its prescaler isn't 1
+    pub apb1_tim_clk : u32,
+    pub apb2_tim_clk : u32,
+}
+
+// in hz, i.e. "sys 48000000 ahb 48000000 apb1 24000000 (tim 48000000) apb2 48000000 (tim 48000000)"
+impl fmt::Display for ClockSpeeds {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "sys {} ahb {} apb1 {} (tim {}) apb2 {} (tim {})",
+            self.sys_clk, self.ahb_clk, self.apb1_clk, self.apb1_tim_clk, self.apb2_clk, self.apb2_tim_clk)
+    }
+}
+
+fn timer_clock(ahb : u32, apb : u32) -> u32 {
+    if apb == ahb { apb } else { apb * 2 }
 }
 
 pub struct ClockConfig {
@@ -359,7 +375,9 @@ impl ClockConfig {
             sys_clk : sc,
             ahb_clk : ahb,
             apb1_clk : apb1,
-            apb2_clk : apb2
+            apb2_clk : apb2,
+            apb1_tim_clk : timer_clock(ahb, apb1),
+            apb2_tim_clk : timer_clock(ahb, apb2),
         }
     }
 
@@ -435,6 +453,8 @@ impl ClockConfig {
             ahb_clk : ahb,
             apb1_clk: apb1,
             apb2_clk: apb2,
+            apb1_tim_clk : timer_clock(ahb, apb1),
+            apb2_tim_clk : timer_clock(ahb, apb2),
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,263 @@
+use stm32f103xx::{DMA1, RCC, Interrupt};
+use cortex_m::peripheral::NVIC;
+
+#[derive(Debug)]
+pub enum DmaError {
+    ChannelBusy,
+    EmptyTransfer,
+    TransferError,
+}
+
+type DmaResult<T> = Result<T, DmaError>;
+
+static mut CALLBACKS : [Option<fn()>; 7] = [None; 7];
+
+const CCR_EN : u32 = 1 << 0;
+const CCR_TCIE : u32 = 1 << 1;
+const CCR_TEIE : u32 = 1 << 3;
+const CCR_DIR : u32 = 1 << 4;
+const CCR_MINC : u32 = 1 << 7;
+
+const FLAG_GIF : u32 = 1 << 0;
+const FLAG_TCIF : u32 = 1 << 1;
+const FLAG_TEIF : u32 = 1 << 3;
+
+// each channel has its own register types, the body is expanded once per
+// channel with the ccr, cndtr, cpar and cmar registers of the channel
+macro_rules! channel_regs {
+    ($ch:expr, |$ccr:ident, $cndtr:ident, $cpar:ident, $cmar:ident| $body:expr) => {{
+        let dma = unsafe { &*DMA1.get() };
+        #[allow(unused_variables, unused_unsafe)]
+        match $ch {
+            Channel::Ch1 => { let ($ccr, $cndtr, $cpar, $cmar) = (&dma.ccr1, &dma.cndtr1, &dma.cpar1, &dma.cmar1); unsafe { $body } },
+            Channel::Ch2 => { let ($ccr, $cndtr, $cpar, $cmar) = (&dma.ccr2, &dma.cndtr2, &dma.cpar2, &dma.cmar2); unsafe { $body } },
+            Channel::Ch3 => { let ($ccr, $cndtr, $cpar, $cmar) = (&dma.ccr3, &dma.cndtr3, &dma.cpar3, &dma.cmar3); unsafe { $body } },
+            Channel::Ch4 => { let ($ccr, $cndtr, $cpar, $cmar) = (&dma.ccr4, &dma.cndtr4, &dma.cpar4, &dma.cmar4); unsafe { $body } },
+            Channel::Ch5 => { let ($ccr, $cndtr, $cpar, $cmar) = (&dma.ccr5, &dma.cndtr5, &dma.cpar5, &dma.cmar5); unsafe { $body } },
+            Channel::Ch6 => { let ($ccr, $cndtr, $cpar, $cmar) = (&dma.ccr6, &dma.cndtr6, &dma.cpar6, &dma.cmar6); unsafe { $body } },
+            Channel::Ch7 => { let ($ccr, $cndtr, $cpar, $cmar) = (&dma.ccr7, &dma.cndtr7, &dma.cpar7, &dma.cmar7); unsafe { $body } },
+        }
+    }};
+}
+
+#[derive(Eq, PartialEq, Copy, Clone)]
+pub enum Channel {
+    Ch1,
+    Ch2,
+    Ch3,
+    Ch4,
+    Ch5,
+    Ch6,
+    Ch7,
+}
+
+impl Channel {
+    pub fn index(&self) -> u32 {
+        (*self as u32)
+    }
+
+    fn flags_shift(&self) -> u32 {
+        self.index() * 4
+    }
+
+    fn interrupt(&self) -> Interrupt {
+        match *self {
+            Channel::Ch1 => Interrupt::DMA1_CHANNEL1,
+            Channel::Ch2 => Interrupt::DMA1_CHANNEL2,
+            Channel::Ch3 => Interrupt::DMA1_CHANNEL3,
+            Channel::Ch4 => Interrupt::DMA1_CHANNEL4,
+            Channel::Ch5 => Interrupt::DMA1_CHANNEL5,
+            Channel::Ch6 => Interrupt::DMA1_CHANNEL6,
+            Channel::Ch7 => Interrupt::DMA1_CHANNEL7,
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Copy, Clone)]
+pub enum Direction {
+    PeriphToMem,
+    MemToPeriph,
+}
+
+#[derive(Eq, PartialEq, Copy, Clone)]
+pub enum Size {
+    Bits8   = 0b00,
+    Bits16  = 0b01,
+    Bits32  = 0b10,
+}
+
+pub struct DmaConfig {
+    channel : Channel,
+    direction : Direction,
+    size : Size,
+    periph_addr : u32,
+    mem_addr : u32,
+    len : u16,
+    mem_increment : bool,
+    callback : Option<fn()>,
+}
+
+impl DmaConfig {
+    pub fn new() -> DmaConfig {
+        DmaConfig {
+            channel : Channel::Ch1,
+            direction : Direction::PeriphToMem,
+            size : Size::Bits8,
+            periph_addr : 0,
+            mem_addr : 0,
+            len : 0,
+            mem_increment : true,
+            callback : None,
+        }
+    }
+
+    pub fn channel(mut self, ch : Channel) -> DmaConfig {
+        self.channel = ch;
+        self
+    }
+
+    pub fn direction(mut self, dir : Direction) -> DmaConfig {
+        self.direction = dir;
+        self
+    }
+
+    pub fn size(mut self, size : Size) -> DmaConfig {
+        self.size = size;
+        self
+    }
+
+    pub fn periph_addr(mut self, addr : u32) -> DmaConfig {
+        self.periph_addr = addr;
+        self
+    }
+
+    pub fn mem_addr(mut self, addr : u32) -> DmaConfig {
+        self.mem_addr = addr;
+        self
+    }
+
+    pub fn len(mut self, len : u16) -> DmaConfig {
+        self.len = len;
+        self
+    }
+
+    pub fn mem_increment(mut self, en : bool) -> DmaConfig {
+        self.mem_increment = en;
+        self
+    }
+
+    // called from the channel interrupt once the transfer is complete
+    pub fn callback(mut self, cb : Option<fn()>) -> DmaConfig {
+        self.callback = cb;
+        self
+    }
+
+    // the memory behind mem_addr must stay valid until the transfer is complete
+    pub fn start(&self) -> DmaResult<Dma> {
+        if self.len == 0 {
+            return Err(DmaError::EmptyTransfer);
+        }
+        let ch = self.channel;
+        unsafe {
+            (*RCC.get()).ahbenr.modify(|_, w| w.dma1en().bit(true));
+
+            if is_enabled(ch) {
+                return Err(DmaError::ChannelBusy);
+            }
+
+            CALLBACKS[ch.index() as usize] = self.callback;
+            (*DMA1.get()).ifcr.write(|w| w.bits(0b1111 << ch.flags_shift()));
+
+            let mut ccr = CCR_TCIE | CCR_TEIE;
+            ccr |= (self.size as u32) << 8; // psize
+            ccr |= (self.size as u32) << 10; // msize
+            if self.direction == Direction::MemToPeriph {
+                ccr |= CCR_DIR;
+            }
+            if self.mem_increment {
+                ccr |= CCR_MINC;
+            }
+            channel_regs!(ch, |ccr_reg, cndtr, cpar, cmar| {
+                cpar.write(|w| w.bits(self.periph_addr));
+                cmar.write(|w| w.bits(self.mem_addr));
+                cndtr.write(|w| w.bits(self.len as u32));
+                ccr_reg.write(|w| w.bits(ccr));
+            });
+
+            (*NVIC.get()).enable(ch.interrupt());
+            set_ccr(ch, ccr | CCR_EN);
+        }
+
+        Ok(Dma { channel : ch })
+    }
+}
+
+pub struct Dma {
+    channel : Channel,
+}
+
+impl Dma {
+    pub fn channel(&self) -> Channel {
+        self.channel
+    }
+
+    // the channel is disabled by the interrupt once the transfer is done
+    pub fn is_complete(&self) -> bool {
+        !is_enabled(self.channel)
+    }
+
+    pub fn remaining(&self) -> u16 {
+        channel_regs!(self.channel, |ccr, cndtr, cpar, cmar| cndtr.read().bits() as u16)
+    }
+
+    pub fn wait(&self) -> DmaResult<()> {
+        while !self.is_complete() {}
+        if self.remaining() != 0 {
+            return Err(DmaError::TransferError);
+        }
+        Ok(())
+    }
+
+    pub fn stop(self) {
+        stop(self.channel);
+    }
+}
+
+pub fn is_enabled(ch : Channel) -> bool {
+    ccr(ch) & CCR_EN != 0
+}
+
+// disables the channel, the transfer can't be resumed
+pub fn stop(ch : Channel) {
+    set_ccr(ch, ccr(ch) & !CCR_EN);
+}
+
+fn ccr(ch : Channel) -> u32 {
+    channel_regs!(ch, |ccr, cndtr, cpar, cmar| ccr.read().bits())
+}
+
+fn set_ccr(ch : Channel, bits : u32) {
+    channel_regs!(ch, |ccr, cndtr, cpar, cmar| ccr.write(|w| w.bits(bits)))
+}
+
+fn handler(ch : Channel) {
+    unsafe {
+        let flags = (*DMA1.get()).isr.read().bits() >> ch.flags_shift();
+        (*DMA1.get()).ifcr.write(|w| w.bits(FLAG_GIF << ch.flags_shift()));
+
+        if flags & (FLAG_TCIF | FLAG_TEIF) != 0 {
+            stop(ch);
+            if let Some(cb) = CALLBACKS[ch.index() as usize] {
+                cb();
+            }
+        }
+    }
+}
+
+pub fn dma1_channel1() { handler(Channel::Ch1); }
+pub fn dma1_channel2() { handler(Channel::Ch2); }
+pub fn dma1_channel3() { handler(Channel::Ch3); }
+pub fn dma1_channel4() { handler(Channel::Ch4); }
+pub fn dma1_channel5() { handler(Channel::Ch5); }
+pub fn dma1_channel6() { handler(Channel::Ch6); }
+pub fn dma1_channel7() { handler(Channel::Ch7); }
@@ -0,0 +1,257 @@
+use cortex_m::interrupt;
+use cortex_m::peripheral::NVIC;
+use stm32f103xx::Interrupt;
+use gpio::*;
+use timing::delay;
+use timing::time::Instant;
+use timing::timer::{self, TimerId, Channel, SlaveMode};
+
+#[derive(Debug)]
+pub enum EncoderError {
+    ConfigError,
+}
+
+type EncoderResult<T> = Result<T, EncoderError>;
+
+// edges counted, both gives 4 counts per encoder line
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum EncoderMode {
+    Ti1,
+    Ti2,
+    Both,
+}
+
+impl EncoderMode {
+    fn slave_mode(&self) -> SlaveMode {
+        match *self {
+            EncoderMode::Ti1 => SlaveMode::Encoder1,
+            EncoderMode::Ti2 => SlaveMode::Encoder2,
+            EncoderMode::Both => SlaveMode::Encoder3,
+        }
+    }
+}
+
+const DIER_UIE : u32 = 1 << 0;
+const DIER_CC3IE : u32 = 1 << 3;
+const DIER_CC4IE : u32 = 1 << 4;
+const SR_CC3IF : u32 = 1 << 3;
+const SR_CC4IF : u32 = 1 << 4;
+
+// the 16 bits counter is sampled on the update event and on compares at a
+// third and two thirds of its range, so it never moves by half its range
+// between two samples and the signed difference isn't ambiguous, with room
+// left for the interrupt latency
+const SAMPLE_LOW : u32 = 0x5555;
+const SAMPLE_HIGH : u32 = 0xAAAA;
+#[derive(Copy, Clone)]
+struct Counter {
+    active : bool,
+    last : u16,
+    position : i64,
+}
+
+const IDLE_COUNTER : Counter = Counter {
+    active : false,
+    last : 0,
+    position : 0,
+};
+
+static mut COUNTERS : [Counter; 4] = [IDLE_COUNTER; 4];
+
+fn index(id : TimerId) -> usize {
+    match id {
+        TimerId::Tim1 => 0,
+        TimerId::Tim2 => 1,
+        TimerId::Tim3 => 2,
+        TimerId::Tim4 => 3,
+    }
+}
+
+pub struct EncoderConfig {
+    timer : TimerId,
+    mode : EncoderMode,
+    filter : u8,
+    inverted : bool,
+}
+
+impl EncoderConfig {
+    // TIM2 to TIM4, A on ch1 and B on ch2
+    pub fn new(timer : TimerId) -> EncoderConfig {
+        EncoderConfig {
+            timer,
+            mode : EncoderMode::Both,
+            filter : 0,
+            inverted : false,
+        }
+    }
+
+    pub fn mode(mut self, mode : EncoderMode) -> EncoderConfig {
+        self.mode = mode;
+        self
+    }
+
+    // ICxF code from 0 to 15, applied to both inputs
+    pub fn filter(mut self, filter : u8) -> EncoderConfig {
+        self.filter = filter;
+        self
+    }
+
+    // counts the other way round
+    pub fn inverted(mut self, inverted : bool) -> EncoderConfig {
+        self.inverted = inverted;
+        self
+    }
+
+    pub fn configure(&self) -> EncoderResult<Encoder> {
+        if self.timer == TimerId::Tim1 || self.filter > 15 {
+            return Err(EncoderError::ConfigError);
+        }
+        for &ch in [Channel::Ch1, Channel::Ch2].iter() {
+            let (port, pin) = timer::channel_pin(self.timer, ch);
+            // open collector encoders need external pull-ups
+            GpioConfig::new()
+                .port(port)
+                .pin(pin)
+                .conf(Conf::FloatingIn)
+                .mode(Mode::Input)
+                .configure()
+                .map_err(|_| EncoderError::ConfigError)?;
+        }
+
+        timer::enable_clock(self.timer);
+        let tim = timer::regs(self.timer);
+        // CC1S and CC2S on their own inputs, same filter
+        let f = (self.filter as u32) << 4 | 0b01;
+        unsafe {
+            tim.cr1.write(|w| w.bits(0));
+            tim.dier.write(|w| w.bits(0));
+            tim.ccer.write(|w| w.bits(0));
+            tim.psc.write(|w| w.bits(0));
+            tim.arr.write(|w| w.bits(0xFFFF));
+            tim.ccmr1_input.write(|w| w.bits(f << 8 | f));
+            // ch3 and ch4 frozen output compares for the samples
+            tim.ccmr2_output.write(|w| w.bits(0));
+            tim.ccr3.write(|w| w.bits(SAMPLE_LOW));
+            tim.ccr4.write(|w| w.bits(SAMPLE_HIGH));
+            // CC1P inverts TI1 and so the counting direction
+            if self.inverted {
+                tim.ccer.write(|w| w.bits(1 << 1));
+            }
+        }
+        timer::set_slave_mode(self.timer, self.mode.slave_mode(), 0);
+        unsafe {
+            tim.cnt.write(|w| w.bits(0));
+            tim.sr.write(|w| w.bits(0));
+        }
+        interrupt::free(|_| unsafe {
+            COUNTERS[index(self.timer)] = Counter {
+                active : true,
+                last : 0,
+                position : 0,
+            };
+        });
+        unsafe {
+            tim.dier.write(|w| w.bits(DIER_UIE | DIER_CC3IE | DIER_CC4IE));
+            (*NVIC.get()).enable(match self.timer {
+                TimerId::Tim2 => Interrupt::TIM2,
+                TimerId::Tim3 => Interrupt::TIM3,
+                _ => Interrupt::TIM4,
+            });
+            tim.cr1.modify(|_, w| w.cen().bit(true));
+        }
+
+        Ok(Encoder {
+            timer : self.timer,
+            last_time : delay::instant(),
+            last_position : 0,
+        })
+    }
+}
+
+pub struct Encoder {
+    timer : TimerId,
+    // previous velocity sample
+    last_time : Instant,
+    last_position : i64,
+}
+
+impl Encoder {
+    // raw hardware counter
+    pub fn counter(&self) -> u16 {
+        timer::regs(self.timer).cnt.read().bits() as u16
+    }
+
+    pub fn position(&self) -> i64 {
+        let id = self.timer;
+        interrupt::free(|_| unsafe {
+            sample(id);
+            COUNTERS[index(id)].position
+        })
+    }
+
+    // wraps like a 32 bits counter
+    pub fn position32(&self) -> i32 {
+        self.position() as i32
+    }
+
+    pub fn set_position(&mut self, position : i64) {
+        let id = self.timer;
+        interrupt::free(|_| unsafe {
+            sample(id);
+            COUNTERS[index(id)].position = position;
+        });
+        self.last_position = position;
+        self.last_time = delay::instant();
+    }
+
+    // counting direction of the last edge
+    pub fn is_counting_down(&self) -> bool {
+        timer::regs(self.timer).cr1.read().dir().bit()
+    }
+
+    // counts per second since the previous call, None when called again
+    // within the same millisecond
+    pub fn velocity(&mut self) -> Option<i32> {
+        let now = delay::instant();
+        let elapsed = now.duration_since(self.last_time).as_ms();
+        if elapsed == 0 {
+            return None;
+        }
+        let position = self.position();
+        let v = (position - self.last_position) * 1000 / elapsed as i64;
+        self.last_position = position;
+        self.last_time = now;
+        Some(v as i32)
+    }
+
+    pub fn release(self) -> TimerId {
+        let tim = timer::regs(self.timer);
+        unsafe {
+            tim.cr1.modify(|_, w| w.cen().bit(false));
+            tim.dier.write(|w| w.bits(0));
+        }
+        timer::set_slave_mode(self.timer, SlaveMode::Disabled, 0);
+        interrupt::free(|_| unsafe { COUNTERS[index(self.timer)] = IDLE_COUNTER });
+        self.timer
+    }
+}
+
+// must run with the interrupts masked or from the timer interrupt
+unsafe fn sample(id : TimerId) {
+    let counter = &mut COUNTERS[index(id)];
+    let cnt = timer::regs(id).cnt.read().bits() as u16;
+    let delta = cnt.wrapping_sub(counter.last) as i16;
+    counter.position += delta as i64;
+    counter.last = cnt;
+}
+
+// called from the timer interrupts, the update flag is cleared by the caller
+pub fn handler(id : TimerId) {
+    unsafe {
+        if !COUNTERS[index(id)].active {
+            return;
+        }
+        sample(id);
+    }
+    timer::clear_flags(id, SR_CC3IF | SR_CC4IF);
+}
@@ -125,14 +125,12 @@ impl GpioConfig {
                     }
 
                     if self.pin.number() > 7 {
-                        (*GPIOA.get()).crh.modify(|_, w| {
-                           w.bits(self.mode.val() << ((self.pin.number() - 8) * 4) |
-                               self.conf.val() << (((self.pin.number() - 8) * 4) + 2))
+                        (*GPIOA.get()).crh.modify(|r, w| {
+                            w.bits(self.cr_bits(r.bits(), self.pin.number() - 8))
                         });
                     } else {
-                        (*GPIOA.get()).crl.modify(|_, w| {
-                            w.bits(self.mode.val() << (self.pin.number() * 4) |
-                                self.conf.val() << ((self.pin.number() * 4) + 2))
+                        (*GPIOA.get()).crl.modify(|r, w| {
+                            w.bits(self.cr_bits(r.bits(), self.pin.number()))
                         });
                     }
                 },
@@ -145,14 +143,12 @@ impl GpioConfig {
                     }
 
                     if self.pin.number() > 7 {
-                        (*GPIOB.get()).crh.modify(|_, w| {
-                            w.bits(self.mode.val() << ((self.pin.number() - 8) * 4) |
-                                self.conf.val() << (((self.pin.number() - 8) * 4) + 2))
+                        (*GPIOB.get()).crh.modify(|r, w| {
+                            w.bits(self.cr_bits(r.bits(), self.pin.number() - 8))
                         });
                     } else {
-                        (*GPIOB.get()).crl.modify(|_, w| {
-                            w.bits(self.mode.val() << (self.pin.number() * 4) |
-                                self.conf.val() << ((self.pin.number() * 4) + 2))
+                        (*GPIOB.get()).crl.modify(|r, w| {
+                            w.bits(self.cr_bits(r.bits(), self.pin.number()))
                         });
                     }
                 },
@@ -165,14 +161,12 @@ impl GpioConfig {
                     }
 
                     if self.pin.number() > 7 {
-                        (*GPIOC.get()).crh.modify(|_, w| {
-                            w.bits((self.mode.val() as u32) << ((self.pin.number() - 8) * 4) |
-                                (self.conf.val() as u32) << (((self.pin.number() - 8) * 4) + 2))
+                        (*GPIOC.get()).crh.modify(|r, w| {
+                            w.bits(self.cr_bits(r.bits(), self.pin.number() - 8))
                         });
                     } else {
-                        (*GPIOC.get()).crl.modify(|_, w| {
-                            w.bits((self.mode.val() as u32) << ((self.pin.number()) * 4) |
-                                (self.conf.val() as u32) << (((self.pin.number()) * 4) + 2))
+                        (*GPIOC.get()).crl.modify(|r, w| {
+                            w.bits(self.cr_bits(r.bits(), self.pin.number()))
                         });
                     }
                 },
@@ -186,6 +180,12 @@ impl GpioConfig {
             port : self.port,
         })
     }
+    // only touch the 4 bits of the pin, the other pins of the port keep their configuration
+    fn cr_bits(&self, current : u32, offset : u32) -> u32 {
+        (current & !(0b1111 << (offset * 4))) |
+            self.mode.val() << (offset * 4) |
+            self.conf.val() << ((offset * 4) + 2)
+    }
 }
 
 pub struct Gpio {
@@ -0,0 +1,26 @@
+// hardware independent parts of the firmware. they are also built for the
+// host so they can be unit tested :
+// cargo test --lib --target x86_64-unknown-linux-gnu
+#![cfg_attr(not(test), no_std)]
+
+// injected by no_std only, the modules use core paths
+#[cfg(test)]
+extern crate core;
+
+pub mod timing {
+    pub mod time;
+    pub mod wheel;
+}
+
+pub mod nor_flash;
+
+pub mod pwm {
+    pub mod period;
+}
+
+pub mod sdcard {
+    mod block;
+    pub mod fat;
+
+    pub use self::block::{BlockDevice, BLOCK_SIZE};
+}
@@ -5,7 +5,11 @@ extern crate cortex_m;
 #[macro_use]
 extern crate cortex_m_rt;
 extern crate cortex_m_semihosting;
+extern crate embedded_hal as hal;
+#[macro_use]
+extern crate nb;
 
+#[macro_use]
 extern crate stm32f103xx;
 
 mod serial;
@@ -13,9 +17,17 @@ mod clocks;
 mod gpio;
 mod timing;
 mod pwm;
+mod capture;
+mod encoder;
 mod i2c;
 mod spi;
 mod analog;
+mod dma;
+mod shell;
+mod nor_flash;
+mod sdcard;
+mod watchdog;
+mod reset;
 
 use clocks::*;
 use gpio::*;
@@ -28,7 +40,28 @@ use cortex_m_semihosting::hio;
 
 exception!(SYS_TICK, delay::ticks);
 
+interrupt!(DMA1_CHANNEL1, dma::dma1_channel1);
+interrupt!(DMA1_CHANNEL2, dma::dma1_channel2);
+interrupt!(DMA1_CHANNEL3, dma::dma1_channel3);
+interrupt!(DMA1_CHANNEL4, dma::dma1_channel4);
+interrupt!(DMA1_CHANNEL5, dma::dma1_channel5);
+interrupt!(DMA1_CHANNEL6, dma::dma1_channel6);
+interrupt!(DMA1_CHANNEL7, dma::dma1_channel7);
+
+interrupt!(SPI1, spi::slave::spi1_handler);
+interrupt!(SPI2, spi::slave::spi2_handler);
+
+interrupt!(TIM1_UP, timing::timer::tim1_up_handler);
+interrupt!(TIM1_CC, timing::timer::tim1_cc_handler);
+interrupt!(TIM2, timing::timer::tim2_handler);
+interrupt!(TIM3, timing::timer::tim3_handler);
+interrupt!(TIM4, timing::timer::tim4_handler);
+
+interrupt!(WWDG, watchdog::wwdg_handler);
+
 fn main() {
+    // cleared right away so the next reset reports only its own cause
+    let reset_cause = reset::ResetCause::take();
 
     let clock_freqs = ClockConfig::new()
         .sys_clk_src(SysClockSrc::PllClock)
@@ -49,6 +82,8 @@ fn main() {
     if clock_freqs.is_err() {
         writeln!(ser, "Error with clock configuration : {:?}", clock_freqs.err());
     }
+    writeln!(ser, "Reset cause : {}", reset_cause);
+    writeln!(ser, "Clocks : {}", ClockConfig::get_speeds());
 
     let led = match GpioConfig::new()
         .pin(Pin(5))
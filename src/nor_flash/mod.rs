@@ -0,0 +1,441 @@
+#[derive(Debug)]
+pub enum FlashError<E> {
+    Device(E),
+    OutOfRange,
+    UnknownDevice,
+}
+
+type FlashResult<T, E> = Result<T, FlashError<E>>;
+
+// link to the flash, each call is one chip select cycle : the command bytes
+// are sent then the data is read or written
+pub trait Transport {
+    type Error;
+
+    fn read(&mut self, cmd : &[u8], buf : &mut [u8]) -> Result<(), Self::Error>;
+    fn write(&mut self, cmd : &[u8], data : &[u8]) -> Result<(), Self::Error>;
+}
+
+pub const PAGE_SIZE : u32 = 256;
+pub const SECTOR_SIZE : u32 = 4 * 1024;
+pub const BLOCK32_SIZE : u32 = 32 * 1024;
+pub const BLOCK64_SIZE : u32 = 64 * 1024;
+
+const WINBOND_ID : u8 = 0xEF;
+
+const CMD_WRITE_ENABLE : u8 = 0x06;
+const CMD_READ_STATUS1 : u8 = 0x05;
+const CMD_READ : u8 = 0x03;
+const CMD_FAST_READ : u8 = 0x0B;
+const CMD_PAGE_PROGRAM : u8 = 0x02;
+const CMD_SECTOR_ERASE : u8 = 0x20;
+const CMD_BLOCK32_ERASE : u8 = 0x52;
+const CMD_BLOCK64_ERASE : u8 = 0xD8;
+const CMD_CHIP_ERASE : u8 = 0xC7;
+const CMD_POWER_DOWN : u8 = 0xB9;
+const CMD_RELEASE_POWER_DOWN : u8 = 0xAB;
+const CMD_JEDEC_ID : u8 = 0x9F;
+
+const STATUS_BUSY : u8 = 1 << 0;
+
+#[derive(Copy, Clone)]
+pub struct JedecId {
+    pub manufacturer : u8,
+    pub memory_type : u8,
+    pub capacity : u8,
+}
+
+impl JedecId {
+    // the capacity code is the log2 of the size in bytes
+    pub fn size(&self) -> u32 {
+        1 << self.capacity
+    }
+}
+
+#[derive(Copy, Clone)]
+pub enum EraseSize {
+    Sector4K,
+    Block32K,
+    Block64K,
+}
+
+impl EraseSize {
+    pub fn as_val(&self) -> u32 {
+        match *self {
+            EraseSize::Sector4K => SECTOR_SIZE,
+            EraseSize::Block32K => BLOCK32_SIZE,
+            EraseSize::Block64K => BLOCK64_SIZE,
+        }
+    }
+
+    fn command(&self) -> u8 {
+        match *self {
+            EraseSize::Sector4K => CMD_SECTOR_ERASE,
+            EraseSize::Block32K => CMD_BLOCK32_ERASE,
+            EraseSize::Block64K => CMD_BLOCK64_ERASE,
+        }
+    }
+}
+
+// winbond W25Qxx spi nor flash, 24 bits addressing
+pub struct W25q<T : Transport> {
+    dev : T,
+    size : u32,
+}
+
+impl<T : Transport> W25q<T> {
+    pub fn new(dev : T) -> FlashResult<W25q<T>, T::Error> {
+        let mut flash = W25q {
+            dev,
+            size : 0,
+        };
+        flash.release_power_down()?;
+        let id = flash.jedec_id()?;
+        if id.manufacturer != WINBOND_ID || id.capacity < 16 || id.capacity > 24 {
+            return Err(FlashError::UnknownDevice);
+        }
+        flash.size = id.size();
+        Ok(flash)
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn jedec_id(&mut self) -> FlashResult<JedecId, T::Error> {
+        let mut buf = [0; 3];
+        self.read_cmd(&[CMD_JEDEC_ID], &mut buf)?;
+        Ok(JedecId {
+            manufacturer : buf[0],
+            memory_type : buf[1],
+            capacity : buf[2],
+        })
+    }
+
+    pub fn read_status(&mut self) -> FlashResult<u8, T::Error> {
+        let mut buf = [0];
+        self.read_cmd(&[CMD_READ_STATUS1], &mut buf)?;
+        Ok(buf[0])
+    }
+
+    pub fn is_busy(&mut self) -> FlashResult<bool, T::Error> {
+        Ok(self.read_status()? & STATUS_BUSY != 0)
+    }
+
+    // program and erase operations return once the flash is ready again
+    pub fn wait_ready(&mut self) -> FlashResult<(), T::Error> {
+        while self.is_busy()? {}
+        Ok(())
+    }
+
+    pub fn read(&mut self, addr : u32, buf : &mut [u8]) -> FlashResult<(), T::Error> {
+        self.check_range(addr, buf.len() as u32)?;
+        self.read_cmd(&command(CMD_READ, addr), buf)
+    }
+
+    // same as read with a dummy byte, allowed at the full spi frequency
+    pub fn fast_read(&mut self, addr : u32, buf : &mut [u8]) -> FlashResult<(), T::Error> {
+        self.check_range(addr, buf.len() as u32)?;
+        let c = command(CMD_FAST_READ, addr);
+        self.read_cmd(&[c[0], c[1], c[2], c[3], 0], buf)
+    }
+
+    // the area must have been erased, data is split on page boundaries
+    pub fn write(&mut self, addr : u32, data : &[u8]) -> FlashResult<(), T::Error> {
+        self.check_range(addr, data.len() as u32)?;
+        let mut addr = addr;
+        let mut data = data;
+        while data.len() > 0 {
+            let room = (PAGE_SIZE - (addr % PAGE_SIZE)) as usize;
+            let n = if data.len() < room { data.len() } else { room };
+            self.page_program(addr, &data[..n])?;
+            addr += n as u32;
+            data = &data[n..];
+        }
+        Ok(())
+    }
+
+    pub fn page_program(&mut self, addr : u32, data : &[u8]) -> FlashResult<(), T::Error> {
+        if data.len() as u32 > PAGE_SIZE - (addr % PAGE_SIZE) {
+            return Err(FlashError::OutOfRange);
+        }
+        self.check_range(addr, data.len() as u32)?;
+        self.write_enable()?;
+        self.write_cmd(&command(CMD_PAGE_PROGRAM, addr), data)?;
+        self.wait_ready()
+    }
+
+    // addr is rounded down to the erase size
+    pub fn erase(&mut self, addr : u32, size : EraseSize) -> FlashResult<(), T::Error> {
+        self.check_range(addr, 1)?;
+        let addr = addr - (addr % size.as_val());
+        self.write_enable()?;
+        self.write_cmd(&command(size.command(), addr), &[])?;
+        self.wait_ready()
+    }
+
+    pub fn erase_chip(&mut self) -> FlashResult<(), T::Error> {
+        self.write_enable()?;
+        self.write_cmd(&[CMD_CHIP_ERASE], &[])?;
+        self.wait_ready()
+    }
+
+    // only release_power_down is accepted afterwards
+    pub fn power_down(&mut self) -> FlashResult<(), T::Error> {
+        self.write_cmd(&[CMD_POWER_DOWN], &[])
+    }
+
+    pub fn release_power_down(&mut self) -> FlashResult<(), T::Error> {
+        self.write_cmd(&[CMD_RELEASE_POWER_DOWN], &[])?;
+        // tRES1 is 3us, a status read takes longer than that at any spi speed
+        self.read_status()?;
+        Ok(())
+    }
+
+    pub fn release(self) -> T {
+        self.dev
+    }
+
+    fn write_enable(&mut self) -> FlashResult<(), T::Error> {
+        self.write_cmd(&[CMD_WRITE_ENABLE], &[])
+    }
+
+    fn read_cmd(&mut self, cmd : &[u8], buf : &mut [u8]) -> FlashResult<(), T::Error> {
+        self.dev.read(cmd, buf).map_err(FlashError::Device)
+    }
+
+    fn write_cmd(&mut self, cmd : &[u8], data : &[u8]) -> FlashResult<(), T::Error> {
+        self.dev.write(cmd, data).map_err(FlashError::Device)
+    }
+
+    fn check_range(&self, addr : u32, len : u32) -> FlashResult<(), T::Error> {
+        if addr >= self.size || len > self.size - addr {
+            return Err(FlashError::OutOfRange);
+        }
+        Ok(())
+    }
+}
+
+fn command(cmd : u8, addr : u32) -> [u8; 4] {
+    [cmd, (addr >> 16) as u8, (addr >> 8) as u8, addr as u8]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 64 KiB W25Q flash in memory, behaving like the real one for the
+    // commands used by the driver
+    struct SimFlash {
+        mem : Vec<u8>,
+        capacity : u8,
+        write_enabled : bool,
+        // status reads left before the current operation is done
+        busy : u32,
+        busy_reads : u32,
+        programs : Vec<(u32, usize)>,
+        last_cmd : Vec<u8>,
+    }
+
+    // status reads an operation stays busy for
+    const BUSY_READS : u32 = 3;
+
+    impl SimFlash {
+        fn new() -> SimFlash {
+            SimFlash {
+                mem : vec![0xFF; 1 << 16],
+                capacity : 16,
+                write_enabled : false,
+                busy : 0,
+                busy_reads : 0,
+                programs : Vec::new(),
+                last_cmd : Vec::new(),
+            }
+        }
+
+        fn start_operation(&mut self) -> Result<(), ()> {
+            if !self.write_enabled || self.busy > 0 {
+                return Err(());
+            }
+            self.write_enabled = false;
+            self.busy = BUSY_READS;
+            Ok(())
+        }
+    }
+
+    fn address(cmd : &[u8]) -> usize {
+        (cmd[1] as usize) << 16 | (cmd[2] as usize) << 8 | cmd[3] as usize
+    }
+
+    impl Transport for SimFlash {
+        type Error = ();
+
+        fn read(&mut self, cmd : &[u8], buf : &mut [u8]) -> Result<(), ()> {
+            self.last_cmd = cmd.to_vec();
+            match cmd[0] {
+                CMD_JEDEC_ID => buf.copy_from_slice(&[WINBOND_ID, 0x40, self.capacity]),
+                CMD_READ_STATUS1 => {
+                    if self.busy > 0 {
+                        self.busy -= 1;
+                        self.busy_reads += 1;
+                        buf[0] = STATUS_BUSY;
+                    } else {
+                        buf[0] = 0;
+                    }
+                },
+                CMD_READ | CMD_FAST_READ => {
+                    let len = if cmd[0] == CMD_READ { 4 } else { 5 };
+                    if cmd.len() != len || self.busy > 0 {
+                        return Err(());
+                    }
+                    let a = address(cmd);
+                    buf.copy_from_slice(&self.mem[a..a + buf.len()]);
+                },
+                _ => return Err(()),
+            }
+            Ok(())
+        }
+
+        fn write(&mut self, cmd : &[u8], data : &[u8]) -> Result<(), ()> {
+            self.last_cmd = cmd.to_vec();
+            match cmd[0] {
+                CMD_WRITE_ENABLE => self.write_enabled = true,
+                CMD_POWER_DOWN | CMD_RELEASE_POWER_DOWN => {},
+                CMD_PAGE_PROGRAM => {
+                    self.start_operation()?;
+                    let a = address(cmd);
+                    let page = a - a % PAGE_SIZE as usize;
+                    // the address wraps inside the page like on the real chip
+                    for (i, &b) in data.iter().enumerate() {
+                        let offset = (a % PAGE_SIZE as usize + i) % PAGE_SIZE as usize;
+                        self.mem[page + offset] &= b;
+                    }
+                    self.programs.push((a as u32, data.len()));
+                },
+                CMD_SECTOR_ERASE | CMD_BLOCK32_ERASE | CMD_BLOCK64_ERASE => {
+                    self.start_operation()?;
+                    let size = match cmd[0] {
+                        CMD_SECTOR_ERASE => SECTOR_SIZE,
+                        CMD_BLOCK32_ERASE => BLOCK32_SIZE,
+                        _ => BLOCK64_SIZE,
+                    } as usize;
+                    let a = address(cmd);
+                    if a % size != 0 {
+                        return Err(());
+                    }
+                    for b in self.mem[a..a + size].iter_mut() {
+                        *b = 0xFF;
+                    }
+                },
+                CMD_CHIP_ERASE => {
+                    self.start_operation()?;
+                    for b in self.mem.iter_mut() {
+                        *b = 0xFF;
+                    }
+                },
+                _ => return Err(()),
+            }
+            Ok(())
+        }
+    }
+
+    fn flash() -> W25q<SimFlash> {
+        W25q::new(SimFlash::new()).unwrap()
+    }
+
+    #[test]
+    fn jedec_id_gives_the_size() {
+        let mut flash = flash();
+        let id = flash.jedec_id().unwrap();
+        assert_eq!(id.manufacturer, WINBOND_ID);
+        assert_eq!(id.memory_type, 0x40);
+        assert_eq!(flash.size(), 1 << 16);
+    }
+
+    #[test]
+    fn unknown_device_is_rejected() {
+        let mut sim = SimFlash::new();
+        sim.capacity = 8;
+        match W25q::new(sim) {
+            Err(FlashError::UnknownDevice) => {},
+            _ => panic!("small capacity accepted"),
+        }
+    }
+
+    #[test]
+    fn read_and_fast_read() {
+        let mut sim = SimFlash::new();
+        for (i, b) in sim.mem.iter_mut().enumerate() {
+            *b = i as u8 ^ (i >> 8) as u8;
+        }
+        let mut flash = W25q::new(sim).unwrap();
+        let mut buf = [0; 16];
+        flash.read(0x1234, &mut buf).unwrap();
+        assert_eq!(&buf[..], &flash.dev.mem[0x1234..0x1244]);
+        assert_eq!(flash.dev.last_cmd, vec![CMD_READ, 0x00, 0x12, 0x34]);
+
+        let mut fast = [0; 16];
+        flash.fast_read(0x1234, &mut fast).unwrap();
+        assert_eq!(buf, fast);
+        // dummy byte after the address
+        assert_eq!(flash.dev.last_cmd, vec![CMD_FAST_READ, 0x00, 0x12, 0x34, 0]);
+    }
+
+    #[test]
+    fn read_out_of_range() {
+        let mut flash = flash();
+        let mut buf = [0; 2];
+        match flash.read(0xFFFF, &mut buf) {
+            Err(FlashError::OutOfRange) => {},
+            _ => panic!("read past the end accepted"),
+        }
+    }
+
+    #[test]
+    fn write_is_split_on_page_boundaries() {
+        let mut flash = flash();
+        let data : Vec<u8> = (0..300).map(|i| i as u8).collect();
+        flash.write(250, &data).unwrap();
+        assert_eq!(flash.dev.programs, vec![(250, 6), (256, 256), (512, 38)]);
+        let mut buf = vec![0; 300];
+        flash.read(250, &mut buf).unwrap();
+        assert_eq!(buf, data);
+        // nothing wrapped to the start of the first page
+        assert!(flash.dev.mem[..250].iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn page_program_across_a_page_is_rejected() {
+        let mut flash = flash();
+        match flash.page_program(250, &[0; 7]) {
+            Err(FlashError::OutOfRange) => {},
+            _ => panic!("page overflow accepted"),
+        }
+        assert!(flash.dev.programs.is_empty());
+    }
+
+    #[test]
+    fn erase_rounds_down_to_the_sector() {
+        let mut flash = flash();
+        for b in flash.dev.mem.iter_mut() {
+            *b = 0;
+        }
+        flash.erase(SECTOR_SIZE + 100, EraseSize::Sector4K).unwrap();
+        let mem = &flash.dev.mem;
+        let sector = SECTOR_SIZE as usize;
+        assert!(mem[..sector].iter().all(|&b| b == 0));
+        assert!(mem[sector..2 * sector].iter().all(|&b| b == 0xFF));
+        assert!(mem[2 * sector..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn operations_wait_while_busy() {
+        let mut flash = flash();
+        flash.page_program(0, &[1, 2, 3]).unwrap();
+        assert_eq!(flash.dev.busy_reads, BUSY_READS);
+        assert!(!flash.is_busy().unwrap());
+        flash.erase_chip().unwrap();
+        assert_eq!(flash.dev.busy_reads, 2 * BUSY_READS);
+        assert_eq!(flash.dev.mem[0], 0xFF);
+    }
+}
@@ -0,0 +1,188 @@
+use stm32f103xx::TIM1;
+use gpio::*;
+use timing::timer::{self, TimerId, Channel};
+use pwm::*;
+
+// level of the break input that disables the outputs
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum BreakPolarity {
+    ActiveLow,
+    ActiveHigh,
+}
+
+// longest dead time, in timer clock ticks
+pub const MAX_DEAD_TIME_TICKS : u32 = 1008;
+
+const BDTR_OSSI : u32 = 1 << 10;
+const BDTR_OSSR : u32 = 1 << 11;
+const BDTR_BKE : u32 = 1 << 12;
+const BDTR_BKP : u32 = 1 << 13;
+const BDTR_AOE : u32 = 1 << 14;
+const BDTR_MOE : u32 = 1 << 15;
+
+const SR_BIF : u32 = 1 << 7;
+
+// tim1 options, configure fails on the other timers when one of them is used
+impl PwmConfig {
+    // CHxN output on ch1 to ch3, driven with the inverse of CHx plus the dead time
+    pub fn complementary(mut self, ch : Channel) -> PwmConfig {
+        self.complementary[ch.index()] = true;
+        self
+    }
+
+    // inserted on every edge between CHx and CHxN
+    pub fn dead_time_ns(mut self, ns : u32) -> PwmConfig {
+        self.dead_time = ns;
+        self
+    }
+
+    // BKIN on PB12 clears MOE when active
+    pub fn break_input(mut self, pol : BreakPolarity) -> PwmConfig {
+        self.break_input = Some(pol);
+        self
+    }
+
+    // MOE is set again at the next update event once the break is released
+    pub fn automatic_output(mut self, aoe : bool) -> PwmConfig {
+        self.automatic_output = aoe;
+        self
+    }
+}
+
+impl Pwm {
+    // MOE, all the tim1 outputs are off while cleared
+    pub fn set_main_output(&mut self, enable : bool) {
+        if self.timer != TimerId::Tim1 {
+            return;
+        }
+        unsafe {
+            (*TIM1.get()).bdtr.modify(|r, w| {
+                w.bits(if enable { r.bits() | BDTR_MOE } else { r.bits() & !BDTR_MOE })
+            });
+        }
+    }
+
+    pub fn main_output_enabled(&self) -> bool {
+        self.timer == TimerId::Tim1 && bdtr() & BDTR_MOE != 0
+    }
+
+    // a break event happened since the last clear_break
+    pub fn break_occurred(&self) -> bool {
+        self.timer == TimerId::Tim1 && timer::regs(self.timer).sr.read().bits() & SR_BIF != 0
+    }
+
+    // MOE has to be set again afterwards unless automatic output is used
+    pub fn clear_break(&mut self) {
+        if self.timer != TimerId::Tim1 {
+            return;
+        }
+        timer::clear_flags(self.timer, SR_BIF);
+    }
+
+    pub fn set_dead_time_ns(&mut self, ns : u32) -> PwmResult<()> {
+        if self.timer != TimerId::Tim1 {
+            return Err(PwmError::ConfigError);
+        }
+        let dtg = dead_time_code(timer::clock(TimerId::Tim1), ns)?;
+        unsafe {
+            (*TIM1.get()).bdtr.modify(|r, w| w.bits(r.bits() & !0xFF | dtg as u32));
+        }
+        Ok(())
+    }
+
+    // CCxNP, independent from the CHx polarity
+    pub fn set_complementary_polarity(&mut self, ch : Channel, pol : Polarity) -> PwmResult<()> {
+        if !self.complementary[ch.index()] {
+            return Err(PwmError::ConfigError);
+        }
+        let bit = 1 << (ch.index() * 4 + 3);
+        unsafe {
+            timer::regs(TimerId::Tim1).ccer.modify(|r, w| {
+                w.bits(if pol == Polarity::ActiveLow { r.bits() | bit } else { r.bits() & !bit })
+            });
+        }
+        Ok(())
+    }
+}
+
+pub fn configure(cfg : &PwmConfig) -> PwmResult<()> {
+    if cfg.complementary[Channel::Ch4.index()] {
+        return Err(PwmError::ConfigError);
+    }
+    for i in 0..3 {
+        if cfg.complementary[i] {
+            if cfg.channels[i].is_none() {
+                return Err(PwmError::ConfigError);
+            }
+            configure_pin(Port::B, Pin(13 + i as u32), Conf::AltFnPushPullOut, Mode::Output50MHz)?;
+        }
+    }
+
+    let mut bdtr = dead_time_code(timer::clock(TimerId::Tim1), cfg.dead_time)? as u32;
+    // disabled outputs are driven to their idle level instead of floating
+    bdtr |= BDTR_OSSI | BDTR_OSSR;
+    if let Some(pol) = cfg.break_input {
+        configure_pin(Port::B, Pin(12), Conf::FloatingIn, Mode::Input)?;
+        bdtr |= BDTR_BKE;
+        if pol == BreakPolarity::ActiveHigh {
+            bdtr |= BDTR_BKP;
+        }
+    }
+    if cfg.automatic_output {
+        bdtr |= BDTR_AOE;
+    }
+    unsafe {
+        (*TIM1.get()).bdtr.write(|w| w.bits(bdtr));
+    }
+    // a break may have been latched while configuring
+    timer::clear_flags(TimerId::Tim1, SR_BIF);
+    unsafe {
+        (*TIM1.get()).bdtr.write(|w| w.bits(bdtr | BDTR_MOE));
+    }
+    Ok(())
+}
+
+// CCxNE
+pub fn enable_complementary(ch : Channel, enable : bool) {
+    let bit = 1 << (ch.index() * 4 + 2);
+    unsafe {
+        timer::regs(TimerId::Tim1).ccer.modify(|r, w| {
+            w.bits(if enable { r.bits() | bit } else { r.bits() & !bit })
+        });
+    }
+}
+
+// DTG field of BDTR, rounded up. the dead time clock is the timer clock (CKD = 0):
+// 0xxxxxxx : dtg ticks, 0 to 127
+// 10xxxxxx : (64 + dtg[5:0]) * 2 ticks, 128 to 254
+// 110xxxxx : (32 + dtg[4:0]) * 8 ticks, 256 to 504
+// 111xxxxx : (32 + dtg[4:0]) * 16 ticks, 512 to 1008
+pub fn dead_time_code(tim_clk : u32, ns : u32) -> PwmResult<u8> {
+    let ticks = ((ns as u64 * tim_clk as u64 + 999_999_999) / 1_000_000_000) as u32;
+    let code = if ticks <= 127 {
+        ticks
+    } else if ticks <= 254 {
+        0b1000_0000 | ((ticks + 1) / 2 - 64)
+    } else if ticks <= 504 {
+        0b1100_0000 | ((ticks + 7) / 8 - 32)
+    } else if ticks <= MAX_DEAD_TIME_TICKS {
+        0b1110_0000 | ((ticks + 15) / 16 - 32)
+    } else {
+        return Err(PwmError::InvalidDeadTime);
+    };
+    Ok(code as u8)
+}
+
+fn bdtr() -> u32 {
+    unsafe { (*TIM1.get()).bdtr.read().bits() }
+}
+
+fn configure_pin(port : Port, pin : Pin, conf : Conf, mode : Mode) -> PwmResult<Gpio> {
+    GpioConfig::new()
+        .port(port)
+        .pin(pin)
+        .conf(conf)
+        .mode(mode)
+        .configure()
+        .map_err(|_| PwmError::ConfigError)
+}
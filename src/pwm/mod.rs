@@ -0,0 +1,373 @@
+use hal;
+use gpio::*;
+use timing::timer::{self, TimerId, Channel, OutputMode, CountMode, TimerError};
+
+pub mod advanced;
+pub mod period;
+
+pub use self::advanced::BreakPolarity;
+
+#[derive(Debug)]
+pub enum PwmError {
+    InvalidFrequency,
+    InvalidDuty,
+    InvalidDeadTime,
+    ConfigError,
+}
+
+impl From<TimerError> for PwmError {
+    fn from(e : TimerError) -> PwmError {
+        match e {
+            TimerError::InvalidFrequency => PwmError::InvalidFrequency,
+            _ => PwmError::ConfigError,
+        }
+    }
+}
+
+type PwmResult<T> = Result<T, PwmError>;
+
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+pub struct PwmConfig {
+    timer : TimerId,
+    frequency : u32,
+    count_mode : CountMode,
+    // polarity of the used channels
+    channels : [Option<Polarity>; 4],
+    // tim1 only
+    complementary : [bool; 4],
+    dead_time : u32,
+    break_input : Option<BreakPolarity>,
+    automatic_output : bool,
+}
+
+impl PwmConfig {
+    pub fn new(timer : TimerId) -> PwmConfig {
+        PwmConfig {
+            timer,
+            frequency : 1000,
+            count_mode : CountMode::Up,
+            channels : [None; 4],
+            complementary : [false; 4],
+            dead_time : 0,
+            break_input : None,
+            automatic_output : false,
+        }
+    }
+
+    pub fn frequency(mut self, hz : u32) -> PwmConfig {
+        self.frequency = hz;
+        self
+    }
+
+    // center-aligned pwm is symmetric around the counter underflow, the
+    // frequency stays the pwm frequency
+    pub fn count_mode(mut self, mode : CountMode) -> PwmConfig {
+        self.count_mode = mode;
+        self
+    }
+
+    // the channel pin is configured, the output stays disabled until enable
+    pub fn channel(mut self, ch : Channel, pol : Polarity) -> PwmConfig {
+        self.channels[ch.index()] = Some(pol);
+        self
+    }
+
+    pub fn configure(&self) -> PwmResult<Pwm> {
+        if self.timer != TimerId::Tim1 && self.is_advanced() {
+            return Err(PwmError::ConfigError);
+        }
+        let (psc, arr) = prescalers(self.timer, self.count_mode, self.frequency)?;
+        timer::enable_clock(self.timer);
+        let tim = timer::regs(self.timer);
+        unsafe {
+            tim.cr1.write(|w| w.bits(0));
+        }
+        timer::set_count_mode(self.timer, self.count_mode)?;
+        unsafe {
+            tim.psc.write(|w| w.bits(psc as u32));
+            tim.arr.write(|w| w.bits(arr as u32));
+            tim.cr1.modify(|_, w| w.arpe().bit(true));
+        }
+
+        let all = [Channel::Ch1, Channel::Ch2, Channel::Ch3, Channel::Ch4];
+        for &ch in all.iter() {
+            if let Some(pol) = self.channels[ch.index()] {
+                let (port, pin) = timer::channel_pin(self.timer, ch);
+                GpioConfig::new()
+                    .port(port)
+                    .pin(pin)
+                    .conf(Conf::AltFnPushPullOut)
+                    .mode(Mode::Output50MHz)
+                    .configure()
+                    .map_err(|_| PwmError::ConfigError)?;
+                timer::enable_output(self.timer, ch, false);
+                timer::set_output_mode(self.timer, ch, OutputMode::Pwm1);
+                timer::set_output_polarity(self.timer, ch, pol == Polarity::ActiveLow);
+                timer::set_compare(self.timer, ch, 0);
+            }
+        }
+
+        unsafe {
+            // load the preloaded registers
+            tim.egr.write(|w| w.ug().bit(true));
+            tim.sr.write(|w| w.bits(0));
+        }
+        // the advanced timer outputs are gated by the main output enable
+        if self.timer == TimerId::Tim1 {
+            advanced::configure(self)?;
+        }
+        unsafe {
+            tim.cr1.modify(|_, w| w.cen().bit(true));
+        }
+
+        Ok(Pwm {
+            timer : self.timer,
+            frequency : self.frequency,
+            count_mode : self.count_mode,
+            arr,
+            channels : self.channels,
+            complementary : self.complementary,
+        })
+    }
+
+    fn is_advanced(&self) -> bool {
+        self.complementary.iter().any(|&c| c) || self.dead_time != 0 || self.break_input.is_some()
+            || self.automatic_output
+    }
+}
+
+pub struct Pwm {
+    timer : TimerId,
+    frequency : u32,
+    count_mode : CountMode,
+    arr : u16,
+    channels : [Option<Polarity>; 4],
+    complementary : [bool; 4],
+}
+
+impl Pwm {
+    pub fn timer(&self) -> TimerId {
+        self.timer
+    }
+
+    pub fn frequency(&self) -> u32 {
+        self.frequency
+    }
+
+    pub fn count_mode(&self) -> CountMode {
+        self.count_mode
+    }
+
+    // the counter is stopped where it is, the outputs keep their level
+    pub fn stop(&mut self) {
+        timer::regs(self.timer).cr1.modify(|_, w| w.cen().bit(false));
+    }
+
+    pub fn start(&mut self) {
+        timer::regs(self.timer).cr1.modify(|_, w| w.cen().bit(true));
+    }
+
+    pub fn is_running(&self) -> bool {
+        timer::regs(self.timer).cr1.read().cen().bit()
+    }
+
+    // TRGO of this timer, i.e. MasterMode::Update in a center-aligned mode
+    // for an adc trigger in the middle of the pulses, or MasterMode::Enable
+    // to start other timers with timer::synchronize
+    pub fn set_master_mode(&mut self, mode : timer::MasterMode) {
+        timer::set_master_mode(self.timer, mode);
+    }
+
+    // duty in ticks, max_duty is 100%
+    pub fn max_duty(&self) -> u16 {
+        period::max_duty(self.arr, self.count_mode.is_center_aligned())
+    }
+
+    pub fn enable(&mut self, ch : Channel) -> PwmResult<()> {
+        self.check_channel(ch)?;
+        timer::enable_output(self.timer, ch, true);
+        if self.complementary[ch.index()] {
+            advanced::enable_complementary(ch, true);
+        }
+        Ok(())
+    }
+
+    pub fn disable(&mut self, ch : Channel) -> PwmResult<()> {
+        self.check_channel(ch)?;
+        timer::enable_output(self.timer, ch, false);
+        if self.complementary[ch.index()] {
+            advanced::enable_complementary(ch, false);
+        }
+        Ok(())
+    }
+
+    pub fn is_enabled(&self, ch : Channel) -> bool {
+        timer::output_enabled(self.timer, ch)
+    }
+
+    pub fn duty(&self, ch : Channel) -> u16 {
+        timer::compare(self.timer, ch)
+    }
+
+    // takes effect at the next period
+    pub fn set_duty(&mut self, ch : Channel, ticks : u16) -> PwmResult<()> {
+        self.check_channel(ch)?;
+        if ticks > self.max_duty() {
+            return Err(PwmError::InvalidDuty);
+        }
+        timer::set_compare(self.timer, ch, ticks);
+        Ok(())
+    }
+
+    // duty as num / den, i.e. set_duty_fraction(ch, 1, 4) for 25%
+    pub fn set_duty_fraction(&mut self, ch : Channel, num : u32, den : u32) -> PwmResult<()> {
+        if den == 0 || num > den {
+            return Err(PwmError::InvalidDuty);
+        }
+        let ticks = (self.max_duty() as u32 * num) / den;
+        self.set_duty(ch, ticks as u16)
+    }
+
+    pub fn set_polarity(&mut self, ch : Channel, pol : Polarity) -> PwmResult<()> {
+        self.check_channel(ch)?;
+        timer::set_output_polarity(self.timer, ch, pol == Polarity::ActiveLow);
+        self.channels[ch.index()] = Some(pol);
+        Ok(())
+    }
+
+    // the duty ratios are kept
+    pub fn set_frequency(&mut self, hz : u32) -> PwmResult<()> {
+        let (psc, arr) = prescalers(self.timer, self.count_mode, hz)?;
+        let old_max = self.max_duty() as u32;
+        let tim = timer::regs(self.timer);
+        unsafe {
+            tim.psc.write(|w| w.bits(psc as u32));
+            tim.arr.write(|w| w.bits(arr as u32));
+        }
+        self.arr = arr;
+        self.frequency = hz;
+
+        let all = [Channel::Ch1, Channel::Ch2, Channel::Ch3, Channel::Ch4];
+        for &ch in all.iter() {
+            if self.channels[ch.index()].is_some() {
+                let duty = timer::compare(self.timer, ch) as u32;
+                let ticks = duty * self.max_duty() as u32 / old_max;
+                timer::set_compare(self.timer, ch, ticks as u16);
+            }
+        }
+        Ok(())
+    }
+
+    // single channel view for drivers taking a PwmPin
+    pub fn channel<'a>(&'a mut self, ch : Channel) -> PwmResult<PwmChannel<'a>> {
+        self.check_channel(ch)?;
+        Ok(PwmChannel {
+            pwm : self,
+            ch,
+        })
+    }
+
+    // stops the timer and disables the outputs, the pins are left in alternate function
+    pub fn release(self) -> TimerId {
+        let tim = timer::regs(self.timer);
+        unsafe {
+            tim.cr1.modify(|_, w| w.cen().bit(false));
+            tim.ccer.write(|w| w.bits(0));
+        }
+        self.timer
+    }
+
+    fn check_channel(&self, ch : Channel) -> PwmResult<()> {
+        if self.channels[ch.index()].is_none() {
+            return Err(PwmError::ConfigError);
+        }
+        Ok(())
+    }
+}
+
+pub struct PwmChannel<'a> {
+    pwm : &'a mut Pwm,
+    ch : Channel,
+}
+
+// the traits can't return errors, the channel was checked when created
+// and duties above max_duty are clamped
+impl hal::Pwm for Pwm {
+    type Channel = Channel;
+    // period in us, rounded to the nearest one
+    type Time = u32;
+    type Duty = u16;
+
+    fn disable(&mut self, ch : Channel) {
+        let _ = Pwm::disable(self, ch);
+    }
+
+    fn enable(&mut self, ch : Channel) {
+        let _ = Pwm::enable(self, ch);
+    }
+
+    fn get_period(&self) -> u32 {
+        (1_000_000 + self.frequency / 2) / self.frequency
+    }
+
+    fn get_duty(&self, ch : Channel) -> u16 {
+        self.duty(ch)
+    }
+
+    fn get_max_duty(&self) -> u16 {
+        self.max_duty()
+    }
+
+    fn set_duty(&mut self, ch : Channel, duty : u16) {
+        let max = self.max_duty();
+        let _ = Pwm::set_duty(self, ch, if duty > max { max } else { duty });
+    }
+
+    // ignored when 0
+    fn set_period<P>(&mut self, period : P) where P : Into<u32> {
+        let us = period.into();
+        if us != 0 {
+            let _ = self.set_frequency((1_000_000 + us / 2) / us);
+        }
+    }
+}
+
+impl<'a> hal::PwmPin for PwmChannel<'a> {
+    type Duty = u16;
+
+    fn disable(&mut self) {
+        let _ = self.pwm.disable(self.ch);
+    }
+
+    fn enable(&mut self) {
+        let _ = self.pwm.enable(self.ch);
+    }
+
+    fn get_duty(&self) -> u16 {
+        self.pwm.duty(self.ch)
+    }
+
+    fn get_max_duty(&self) -> u16 {
+        self.pwm.max_duty()
+    }
+
+    fn set_duty(&mut self, duty : u16) {
+        let max = self.pwm.max_duty();
+        let _ = self.pwm.set_duty(self.ch, if duty > max { max } else { duty });
+    }
+}
+
+// a center-aligned period is 2 * arr ticks instead of arr + 1
+fn prescalers(id : TimerId, mode : CountMode, hz : u32) -> PwmResult<(u16, u16)> {
+    if mode.is_center_aligned() {
+        let (psc, arr) = timer::prescalers(timer::clock(id), hz.saturating_mul(2))?;
+        Ok((psc, period::center_aligned_reload(arr)))
+    } else {
+        Ok(timer::prescalers(timer::clock(id), hz)?)
+    }
+}
@@ -0,0 +1,42 @@
+// tick arithmetic of a pwm period, without hardware access
+
+// reload for a center-aligned period from timer::prescalers run at twice the
+// frequency : the counter goes up and down so the period is 2 * arr ticks
+// instead of arr + 1. prescalers keeps arr below 0xFFFF so it fits
+pub fn center_aligned_reload(arr : u16) -> u16 {
+    arr + 1
+}
+
+// compare value for a 100% duty. counting up, the output is active while
+// the counter is below ccr, which it always is with arr + 1. counting up
+// and down the counter stops at arr so ccr == arr is already 100%
+pub fn max_duty(arr : u16, center_aligned : bool) -> u16 {
+    if center_aligned {
+        arr
+    } else {
+        arr + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edge_aligned_max_duty() {
+        assert_eq!(max_duty(999, false), 1000);
+        assert_eq!(max_duty(0xFFFE, false), 0xFFFF);
+    }
+
+    #[test]
+    fn center_aligned_max_duty() {
+        // 1 khz from a 72 mhz clock, prescalers gives arr 35999 at 2 khz
+        let arr = center_aligned_reload(35_999);
+        assert_eq!(arr, 36_000);
+        assert_eq!(2 * arr as u32, 72_000);
+        assert_eq!(max_duty(arr, true), 36_000);
+        // largest reload, the full duty still fits in 16 bits
+        let arr = center_aligned_reload(0xFFFE);
+        assert_eq!(max_duty(arr, true), 0xFFFF);
+    }
+}
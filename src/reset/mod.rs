@@ -0,0 +1,121 @@
+use stm32f103xx::RCC;
+use core::fmt;
+
+const CSR_RMVF : u32 = 1 << 24;
+const CSR_PINRSTF : u32 = 1 << 26;
+const CSR_PORRSTF : u32 = 1 << 27;
+const CSR_SFTRSTF : u32 = 1 << 28;
+const CSR_IWDGRSTF : u32 = 1 << 29;
+const CSR_WWDGRSTF : u32 = 1 << 30;
+const CSR_LPWRRSTF : u32 = 1 << 31;
+
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum ResetReason {
+    LowPower,
+    WindowWatchdog,
+    IndependentWatchdog,
+    Software,
+    PowerOn,
+    Pin,
+    Unknown,
+}
+
+// reset flags of RCC CSR. they accumulate over resets until cleared, and
+// every internal reset also pulls NRST so the pin flag comes with the others
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub struct ResetCause {
+    pub pin : bool,
+    pub power_on : bool,
+    pub software : bool,
+    pub independent_watchdog : bool,
+    pub window_watchdog : bool,
+    pub low_power : bool,
+}
+
+impl ResetCause {
+    pub fn read() -> ResetCause {
+        let csr = unsafe { (*RCC.get()).csr.read().bits() };
+        ResetCause {
+            pin : csr & CSR_PINRSTF != 0,
+            power_on : csr & CSR_PORRSTF != 0,
+            software : csr & CSR_SFTRSTF != 0,
+            independent_watchdog : csr & CSR_IWDGRSTF != 0,
+            window_watchdog : csr & CSR_WWDGRSTF != 0,
+            low_power : csr & CSR_LPWRRSTF != 0,
+        }
+    }
+
+    // so the next reset reports only its own cause
+    pub fn clear() {
+        unsafe {
+            (*RCC.get()).csr.modify(|r, w| w.bits(r.bits() | CSR_RMVF));
+        }
+    }
+
+    // read then clear, to call once at boot
+    pub fn take() -> ResetCause {
+        let cause = ResetCause::read();
+        ResetCause::clear();
+        cause
+    }
+
+    // most specific flag, the pin only when nothing else is set
+    pub fn reason(&self) -> ResetReason {
+        if self.low_power {
+            ResetReason::LowPower
+        } else if self.window_watchdog {
+            ResetReason::WindowWatchdog
+        } else if self.independent_watchdog {
+            ResetReason::IndependentWatchdog
+        } else if self.software {
+            ResetReason::Software
+        } else if self.power_on {
+            ResetReason::PowerOn
+        } else if self.pin {
+            ResetReason::Pin
+        } else {
+            ResetReason::Unknown
+        }
+    }
+}
+
+impl fmt::Display for ResetReason {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            ResetReason::LowPower => "low power",
+            ResetReason::WindowWatchdog => "window watchdog",
+            ResetReason::IndependentWatchdog => "independent watchdog",
+            ResetReason::Software => "software",
+            ResetReason::PowerOn => "power on",
+            ResetReason::Pin => "pin",
+            ResetReason::Unknown => "unknown",
+        };
+        f.write_str(s)
+    }
+}
+
+// the reason followed by all the flags set, i.e. "software (pin sft)"
+impl fmt::Display for ResetCause {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (", self.reason())?;
+        let flags = [
+            (self.pin, "pin"),
+            (self.power_on, "por"),
+            (self.software, "sft"),
+            (self.independent_watchdog, "iwdg"),
+            (self.window_watchdog, "wwdg"),
+            (self.low_power, "lpwr"),
+        ];
+        let mut first = true;
+        for &(set, name) in flags.iter() {
+            if set {
+                if !first {
+                    f.write_str(" ")?;
+                }
+                f.write_str(name)?;
+                first = false;
+            }
+        }
+        f.write_str(")")
+    }
+}
@@ -0,0 +1,9 @@
+pub const BLOCK_SIZE : usize = 512;
+
+// anything addressed by 512 bytes blocks, the fat layer only needs this
+pub trait BlockDevice {
+    type Error;
+
+    fn read_block(&mut self, lba : u32, buf : &mut [u8; BLOCK_SIZE]) -> Result<(), Self::Error>;
+    fn write_block(&mut self, lba : u32, buf : &[u8; BLOCK_SIZE]) -> Result<(), Self::Error>;
+}
@@ -0,0 +1,929 @@
+use sdcard::{BlockDevice, BLOCK_SIZE};
+
+#[derive(Debug)]
+pub enum FatError<E> {
+    Device(E),
+    NoFilesystem,
+    Unsupported,
+    InvalidName,
+    NotFound,
+    DirectoryFull,
+    DiskFull,
+    Corrupted,
+}
+
+type FatResult<T, E> = Result<T, FatError<E>>;
+
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum FatType {
+    Fat16,
+    Fat32,
+}
+
+const DIR_ENTRY_SIZE : usize = 32;
+const ENTRIES_PER_SECTOR : usize = BLOCK_SIZE / DIR_ENTRY_SIZE;
+
+const ATTR_VOLUME_ID : u8 = 0x08;
+const ATTR_DIRECTORY : u8 = 0x10;
+const ATTR_ARCHIVE : u8 = 0x20;
+const ATTR_LONG_NAME : u8 = 0x0F;
+
+const ENTRY_END : u8 = 0x00;
+const ENTRY_DELETED : u8 = 0xE5;
+
+const FAT16_EOC : u32 = 0xFFFF;
+const FAT32_EOC : u32 = 0x0FFF_FFFF;
+const FAT32_MASK : u32 = 0x0FFF_FFFF;
+
+#[derive(Copy, Clone)]
+pub struct DirEntry {
+    // 8.3 name, space padded, without the dot
+    pub name : [u8; 11],
+    pub attr : u8,
+    pub cluster : u32,
+    pub size : u32,
+    // where the entry is stored, to update it in place
+    lba : u32,
+    index : usize,
+}
+
+impl DirEntry {
+    pub fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    fn parse(raw : &[u8], lba : u32, index : usize) -> DirEntry {
+        let mut name = [0; 11];
+        name.copy_from_slice(&raw[..11]);
+        DirEntry {
+            name,
+            attr : raw[11],
+            cluster : (read_u16(raw, 20) as u32) << 16 | read_u16(raw, 26) as u32,
+            size : read_u32(raw, 28),
+            lba,
+            index,
+        }
+    }
+
+    fn store(&self, raw : &mut [u8]) {
+        raw[..11].copy_from_slice(&self.name);
+        raw[11] = self.attr;
+        write_u16(raw, 20, (self.cluster >> 16) as u16);
+        write_u16(raw, 26, self.cluster as u16);
+        write_u32(raw, 28, self.size);
+    }
+}
+
+// fat16 and fat32 volumes, 8.3 files in the root directory only
+pub struct FatVolume<D : BlockDevice> {
+    dev : D,
+    fat_type : FatType,
+    sectors_per_cluster : u32,
+    fat_start : u32,
+    fat_size : u32,
+    num_fats : u32,
+    root_dir_start : u32,
+    root_dir_sectors : u32,
+    root_cluster : u32,
+    data_start : u32,
+    cluster_count : u32,
+    buf : [u8; BLOCK_SIZE],
+}
+
+impl<D : BlockDevice> FatVolume<D> {
+    // the volume is either the whole device or the first mbr partition
+    pub fn mount(dev : D) -> FatResult<FatVolume<D>, D::Error> {
+        let mut vol = FatVolume {
+            dev,
+            fat_type : FatType::Fat16,
+            sectors_per_cluster : 0,
+            fat_start : 0,
+            fat_size : 0,
+            num_fats : 0,
+            root_dir_start : 0,
+            root_dir_sectors : 0,
+            root_cluster : 0,
+            data_start : 0,
+            cluster_count : 0,
+            buf : [0; BLOCK_SIZE],
+        };
+
+        vol.load(0)?;
+        if read_u16(&vol.buf, 510) != 0xAA55 {
+            return Err(FatError::NoFilesystem);
+        }
+        let mut start = 0;
+        if !is_boot_sector(&vol.buf) {
+            // first partition entry of the mbr
+            start = read_u32(&vol.buf, 0x1C6);
+            vol.load(start)?;
+            if read_u16(&vol.buf, 510) != 0xAA55 || !is_boot_sector(&vol.buf) {
+                return Err(FatError::NoFilesystem);
+            }
+        }
+
+        let bytes_per_sector = read_u16(&vol.buf, 11) as usize;
+        let sectors_per_cluster = vol.buf[13] as u32;
+        let reserved = read_u16(&vol.buf, 14) as u32;
+        let num_fats = vol.buf[16] as u32;
+        let root_entries = read_u16(&vol.buf, 17) as u32;
+        let mut total = read_u16(&vol.buf, 19) as u32;
+        if total == 0 {
+            total = read_u32(&vol.buf, 32);
+        }
+        let mut fat_size = read_u16(&vol.buf, 22) as u32;
+        if fat_size == 0 {
+            fat_size = read_u32(&vol.buf, 36);
+        }
+        if bytes_per_sector != BLOCK_SIZE || sectors_per_cluster == 0 || num_fats == 0 {
+            return Err(FatError::Unsupported);
+        }
+
+        let root_dir_sectors = (root_entries * DIR_ENTRY_SIZE as u32 + BLOCK_SIZE as u32 - 1) / BLOCK_SIZE as u32;
+        // a malformed bpb can describe more metadata than sectors
+        let data_sectors = total.checked_sub(reserved)
+            .and_then(|s| num_fats.checked_mul(fat_size).and_then(|fats| s.checked_sub(fats)))
+            .and_then(|s| s.checked_sub(root_dir_sectors))
+            .ok_or(FatError::NoFilesystem)?;
+        let cluster_count = data_sectors / sectors_per_cluster;
+
+        // the fat type only depends on the number of clusters, fat12 isn't handled
+        if cluster_count < 4085 {
+            return Err(FatError::Unsupported);
+        }
+        vol.fat_type = if cluster_count < 65525 { FatType::Fat16 } else { FatType::Fat32 };
+
+        vol.sectors_per_cluster = sectors_per_cluster;
+        vol.fat_start = start + reserved;
+        vol.fat_size = fat_size;
+        vol.num_fats = num_fats;
+        vol.root_dir_start = vol.fat_start + num_fats * fat_size;
+        vol.root_dir_sectors = root_dir_sectors;
+        vol.data_start = vol.root_dir_start + root_dir_sectors;
+        vol.cluster_count = cluster_count;
+        if vol.fat_type == FatType::Fat32 {
+            vol.root_cluster = read_u32(&vol.buf, 44);
+        }
+        Ok(vol)
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    pub fn unmount(self) -> D {
+        self.dev
+    }
+
+    // calls f for every file and directory of the root directory
+    pub fn list<F>(&mut self, mut f : F) -> FatResult<(), D::Error>
+        where F : FnMut(&DirEntry)
+    {
+        let mut sector = 0;
+        while let Some(lba) = self.root_sector(sector)? {
+            self.load(lba)?;
+            for i in 0..ENTRIES_PER_SECTOR {
+                let raw = &self.buf[i * DIR_ENTRY_SIZE..(i + 1) * DIR_ENTRY_SIZE];
+                if raw[0] == ENTRY_END {
+                    return Ok(());
+                }
+                if raw[0] == ENTRY_DELETED || raw[11] & ATTR_LONG_NAME == ATTR_LONG_NAME ||
+                    raw[11] & ATTR_VOLUME_ID != 0 {
+                    continue;
+                }
+                f(&DirEntry::parse(raw, lba, i));
+            }
+            sector += 1;
+        }
+        Ok(())
+    }
+
+    pub fn find(&mut self, name : &str) -> FatResult<DirEntry, D::Error> {
+        let short = short_name(name).ok_or(FatError::InvalidName)?;
+        let mut found = None;
+        self.list(|e| {
+            if found.is_none() && e.name == short {
+                found = Some(*e);
+            }
+        })?;
+        found.ok_or(FatError::NotFound)
+    }
+
+    // returns the number of bytes read, 0 at the end of the file
+    pub fn read(&mut self, entry : &DirEntry, offset : u32, buf : &mut [u8]) -> FatResult<usize, D::Error> {
+        if offset >= entry.size {
+            return Ok(0);
+        }
+        let cluster_bytes = self.sectors_per_cluster * BLOCK_SIZE as u32;
+        let mut cluster = entry.cluster;
+        for _ in 0..(offset / cluster_bytes) {
+            cluster = self.next_cluster(cluster)?.ok_or(FatError::Corrupted)?;
+        }
+
+        let mut pos = offset;
+        let mut done = 0;
+        let end = if (entry.size - offset) < buf.len() as u32 { entry.size } else { offset + buf.len() as u32 };
+        while pos < end {
+            if pos != offset && pos % cluster_bytes == 0 {
+                cluster = self.next_cluster(cluster)?.ok_or(FatError::Corrupted)?;
+            }
+            let in_cluster = pos % cluster_bytes;
+            let lba = self.cluster_lba(cluster)? + in_cluster / BLOCK_SIZE as u32;
+            let in_sector = (pos % BLOCK_SIZE as u32) as usize;
+            let mut n = BLOCK_SIZE - in_sector;
+            if n as u32 > end - pos {
+                n = (end - pos) as usize;
+            }
+            self.load(lba)?;
+            buf[done..done + n].copy_from_slice(&self.buf[in_sector..in_sector + n]);
+            done += n;
+            pos += n as u32;
+        }
+        Ok(done)
+    }
+
+    // creates the file or replaces its content. the new data is written
+    // before the entry is switched to it, the old clusters are freed last.
+    // on error an existing file is left as it was and a new one isn't created
+    pub fn write_file(&mut self, name : &str, data : &[u8]) -> FatResult<(), D::Error> {
+        let short = short_name(name).ok_or(FatError::InvalidName)?;
+        let (mut entry, created) = match self.find(name) {
+            Ok(e) => {
+                if e.is_dir() {
+                    return Err(FatError::InvalidName);
+                }
+                (e, false)
+            },
+            Err(FatError::NotFound) => (self.new_entry(short)?, true),
+            Err(e) => return Err(e),
+        };
+
+        let old = entry.cluster;
+        entry.cluster = match self.write_chain(data) {
+            Ok(c) => c,
+            Err(e) => {
+                if created {
+                    let _ = self.remove_entry(&entry);
+                }
+                return Err(e);
+            },
+        };
+        entry.size = data.len() as u32;
+        self.update_entry(&entry)?;
+        self.free_chain(old)
+    }
+
+    pub fn delete(&mut self, name : &str) -> FatResult<(), D::Error> {
+        let entry = self.find(name)?;
+        if entry.is_dir() {
+            return Err(FatError::InvalidName);
+        }
+        self.free_chain(entry.cluster)?;
+        self.remove_entry(&entry)
+    }
+
+    fn new_entry(&mut self, name : [u8; 11]) -> FatResult<DirEntry, D::Error> {
+        let mut sector = 0;
+        while let Some(lba) = self.root_sector(sector)? {
+            self.load(lba)?;
+            for i in 0..ENTRIES_PER_SECTOR {
+                let first = self.buf[i * DIR_ENTRY_SIZE];
+                if first == ENTRY_END || first == ENTRY_DELETED {
+                    let entry = DirEntry {
+                        name,
+                        attr : ATTR_ARCHIVE,
+                        cluster : 0,
+                        size : 0,
+                        lba,
+                        index : i,
+                    };
+                    // an empty file until the data is written, times and reserved fields cleared
+                    for b in self.buf[i * DIR_ENTRY_SIZE..(i + 1) * DIR_ENTRY_SIZE].iter_mut() {
+                        *b = 0;
+                    }
+                    entry.store(&mut self.buf[i * DIR_ENTRY_SIZE..(i + 1) * DIR_ENTRY_SIZE]);
+                    self.store(lba)?;
+                    return Ok(entry);
+                }
+            }
+            sector += 1;
+        }
+        Err(FatError::DirectoryFull)
+    }
+
+    fn remove_entry(&mut self, entry : &DirEntry) -> FatResult<(), D::Error> {
+        self.load(entry.lba)?;
+        self.buf[entry.index * DIR_ENTRY_SIZE] = ENTRY_DELETED;
+        self.store(entry.lba)
+    }
+
+    // data in newly allocated clusters, returns the first one or 0 when
+    // empty. nothing stays allocated on error
+    fn write_chain(&mut self, data : &[u8]) -> FatResult<u32, D::Error> {
+        let mut first = 0;
+        match self.fill_chain(data, &mut first) {
+            Ok(()) => Ok(first),
+            Err(e) => {
+                let _ = self.free_chain(first);
+                Err(e)
+            },
+        }
+    }
+
+    // the chain is always terminated, first is set once the first cluster
+    // is allocated
+    fn fill_chain(&mut self, data : &[u8], first : &mut u32) -> FatResult<(), D::Error> {
+        let cluster_bytes = (self.sectors_per_cluster as usize) * BLOCK_SIZE;
+        let mut prev = 0;
+        for chunk in data.chunks(cluster_bytes) {
+            let cluster = self.allocate(prev)?;
+            if *first == 0 {
+                *first = cluster;
+            }
+            let lba = self.cluster_lba(cluster)?;
+            for (i, sector) in chunk.chunks(BLOCK_SIZE).enumerate() {
+                for b in self.buf.iter_mut() {
+                    *b = 0;
+                }
+                self.buf[..sector.len()].copy_from_slice(sector);
+                self.store(lba + i as u32)?;
+            }
+            prev = cluster;
+        }
+        Ok(())
+    }
+
+    fn update_entry(&mut self, entry : &DirEntry) -> FatResult<(), D::Error> {
+        self.load(entry.lba)?;
+        entry.store(&mut self.buf[entry.index * DIR_ENTRY_SIZE..(entry.index + 1) * DIR_ENTRY_SIZE]);
+        self.store(entry.lba)
+    }
+
+    // lba of the nth sector of the root directory, None past its end
+    fn root_sector(&mut self, n : u32) -> FatResult<Option<u32>, D::Error> {
+        match self.fat_type {
+            FatType::Fat16 => {
+                if n < self.root_dir_sectors {
+                    Ok(Some(self.root_dir_start + n))
+                } else {
+                    Ok(None)
+                }
+            },
+            FatType::Fat32 => {
+                let mut cluster = self.root_cluster;
+                for _ in 0..(n / self.sectors_per_cluster) {
+                    match self.next_cluster(cluster)? {
+                        Some(c) => cluster = c,
+                        None => return Ok(None),
+                    }
+                }
+                Ok(Some(self.cluster_lba(cluster)? + n % self.sectors_per_cluster))
+            },
+        }
+    }
+
+    fn cluster_lba(&self, cluster : u32) -> FatResult<u32, D::Error> {
+        if cluster < 2 || cluster >= self.cluster_count + 2 {
+            return Err(FatError::Corrupted);
+        }
+        Ok(self.data_start + (cluster - 2) * self.sectors_per_cluster)
+    }
+
+    fn fat_entry(&mut self, cluster : u32) -> FatResult<u32, D::Error> {
+        let (lba, offset) = self.fat_position(cluster);
+        self.load(lba)?;
+        Ok(match self.fat_type {
+            FatType::Fat16 => read_u16(&self.buf, offset) as u32,
+            FatType::Fat32 => read_u32(&self.buf, offset) & FAT32_MASK,
+        })
+    }
+
+    // every copy of the fat is updated
+    fn set_fat_entry(&mut self, cluster : u32, value : u32) -> FatResult<(), D::Error> {
+        let (lba, offset) = self.fat_position(cluster);
+        for copy in 0..self.num_fats {
+            let lba = lba + copy * self.fat_size;
+            self.load(lba)?;
+            match self.fat_type {
+                FatType::Fat16 => write_u16(&mut self.buf, offset, value as u16),
+                FatType::Fat32 => {
+                    // the upper 4 bits are reserved and kept
+                    let old = read_u32(&self.buf, offset);
+                    write_u32(&mut self.buf, offset, (old & !FAT32_MASK) | (value & FAT32_MASK));
+                },
+            }
+            self.store(lba)?;
+        }
+        Ok(())
+    }
+
+    fn fat_position(&self, cluster : u32) -> (u32, usize) {
+        let bytes = match self.fat_type {
+            FatType::Fat16 => cluster * 2,
+            FatType::Fat32 => cluster * 4,
+        };
+        (self.fat_start + bytes / BLOCK_SIZE as u32, (bytes % BLOCK_SIZE as u32) as usize)
+    }
+
+    fn next_cluster(&mut self, cluster : u32) -> FatResult<Option<u32>, D::Error> {
+        let next = self.fat_entry(cluster)?;
+        let eoc = match self.fat_type {
+            FatType::Fat16 => next >= 0xFFF8,
+            FatType::Fat32 => next >= 0x0FFF_FFF8,
+        };
+        if eoc {
+            return Ok(None);
+        }
+        if next < 2 {
+            return Err(FatError::Corrupted);
+        }
+        Ok(Some(next))
+    }
+
+    // takes the first free cluster and links it after prev when prev isn't 0
+    fn allocate(&mut self, prev : u32) -> FatResult<u32, D::Error> {
+        let eoc = match self.fat_type {
+            FatType::Fat16 => FAT16_EOC,
+            FatType::Fat32 => FAT32_EOC,
+        };
+        for cluster in 2..(self.cluster_count + 2) {
+            if self.fat_entry(cluster)? == 0 {
+                self.set_fat_entry(cluster, eoc)?;
+                if prev != 0 {
+                    self.set_fat_entry(prev, cluster)?;
+                }
+                return Ok(cluster);
+            }
+        }
+        Err(FatError::DiskFull)
+    }
+
+    fn free_chain(&mut self, first : u32) -> FatResult<(), D::Error> {
+        let mut cluster = first;
+        while cluster >= 2 {
+            let next = self.next_cluster(cluster)?;
+            self.set_fat_entry(cluster, 0)?;
+            cluster = match next {
+                Some(c) => c,
+                None => 0,
+            };
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, lba : u32) -> FatResult<(), D::Error> {
+        self.dev.read_block(lba, &mut self.buf).map_err(FatError::Device)
+    }
+
+    fn store(&mut self, lba : u32) -> FatResult<(), D::Error> {
+        self.dev.write_block(lba, &self.buf).map_err(FatError::Device)
+    }
+}
+
+fn is_boot_sector(buf : &[u8]) -> bool {
+    (buf[0] == 0xEB || buf[0] == 0xE9) && read_u16(buf, 11) as usize == BLOCK_SIZE
+}
+
+// "readme.txt" -> "README  TXT"
+pub fn short_name(name : &str) -> Option<[u8; 11]> {
+    let mut short = [b' '; 11];
+    let bytes = name.as_bytes();
+    let (base, ext) = match bytes.iter().position(|&b| b == b'.') {
+        Some(p) => (&bytes[..p], &bytes[p + 1..]),
+        None => (bytes, &bytes[bytes.len()..]),
+    };
+    if base.len() == 0 || base.len() > 8 || ext.len() > 3 {
+        return None;
+    }
+    for (i, &b) in base.iter().chain(ext.iter()).enumerate() {
+        if !valid_short_char(b) {
+            return None;
+        }
+        let pos = if i < base.len() { i } else { 8 + i - base.len() };
+        short[pos] = if b >= b'a' && b <= b'z' { b - (b'a' - b'A') } else { b };
+    }
+    Some(short)
+}
+
+fn valid_short_char(b : u8) -> bool {
+    match b {
+        b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' => true,
+        b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'(' | b')' | b'-' | b'@' | b'^' | b'_' | b'`' | b'{' | b'}' | b'~' => true,
+        _ => false,
+    }
+}
+
+fn read_u16(buf : &[u8], offset : usize) -> u16 {
+    (buf[offset] as u16) | (buf[offset + 1] as u16) << 8
+}
+
+fn read_u32(buf : &[u8], offset : usize) -> u32 {
+    (read_u16(buf, offset) as u32) | (read_u16(buf, offset + 2) as u32) << 16
+}
+
+fn write_u16(buf : &mut [u8], offset : usize, val : u16) {
+    buf[offset] = val as u8;
+    buf[offset + 1] = (val >> 8) as u8;
+}
+
+fn write_u32(buf : &mut [u8], offset : usize, val : u32) {
+    write_u16(buf, offset, val as u16);
+    write_u16(buf, offset + 2, (val >> 16) as u16);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{self, Read, Seek, SeekFrom, Write};
+    use std::path::PathBuf;
+
+    // disk image in a temporary file, removed when dropped
+    struct FileDisk {
+        file : File,
+        path : PathBuf,
+    }
+
+    impl FileDisk {
+        fn create(name : &str, sectors : u32) -> FileDisk {
+            let path = env::temp_dir().join(format!("fat_{}_{}.img", name, ::std::process::id()));
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&path)
+                .unwrap();
+            // sparse, reads as zeros
+            file.set_len(sectors as u64 * BLOCK_SIZE as u64).unwrap();
+            FileDisk { file, path }
+        }
+    }
+
+    impl Drop for FileDisk {
+        fn drop(&mut self) {
+            fs::remove_file(&self.path).ok();
+        }
+    }
+
+    impl BlockDevice for FileDisk {
+        type Error = io::Error;
+
+        fn read_block(&mut self, lba : u32, buf : &mut [u8; BLOCK_SIZE]) -> io::Result<()> {
+            self.file.seek(SeekFrom::Start(lba as u64 * BLOCK_SIZE as u64))?;
+            self.file.read_exact(buf)
+        }
+
+        fn write_block(&mut self, lba : u32, buf : &[u8; BLOCK_SIZE]) -> io::Result<()> {
+            self.file.seek(SeekFrom::Start(lba as u64 * BLOCK_SIZE as u64))?;
+            self.file.write_all(buf)
+        }
+    }
+
+    // one sector per cluster, two fats
+    struct Layout {
+        fat_type : FatType,
+        start : u32,
+        reserved : u32,
+        root_entries : u32,
+        clusters : u32,
+    }
+
+    const FAT16 : Layout = Layout {
+        fat_type : FatType::Fat16,
+        start : 0,
+        reserved : 1,
+        root_entries : 512,
+        clusters : 4200,
+    };
+
+    // in the first partition of an mbr
+    const FAT32 : Layout = Layout {
+        fat_type : FatType::Fat32,
+        start : 63,
+        reserved : 32,
+        root_entries : 0,
+        clusters : 65600,
+    };
+
+    impl Layout {
+        fn entry_size(&self) -> u32 {
+            if self.fat_type == FatType::Fat16 { 2 } else { 4 }
+        }
+
+        fn fat_size(&self) -> u32 {
+            ((self.clusters + 2) * self.entry_size() + BLOCK_SIZE as u32 - 1) / BLOCK_SIZE as u32
+        }
+
+        fn sectors(&self) -> u32 {
+            let root = self.root_entries * DIR_ENTRY_SIZE as u32 / BLOCK_SIZE as u32;
+            self.reserved + 2 * self.fat_size() + root + self.clusters
+        }
+
+        fn boot_sector(&self) -> [u8; BLOCK_SIZE] {
+            let mut b = [0; BLOCK_SIZE];
+            b[0] = 0xEB;
+            write_u16(&mut b, 11, BLOCK_SIZE as u16);
+            b[13] = 1;
+            write_u16(&mut b, 14, self.reserved as u16);
+            b[16] = 2;
+            write_u16(&mut b, 17, self.root_entries as u16);
+            write_u16(&mut b, 510, 0xAA55);
+            match self.fat_type {
+                FatType::Fat16 => {
+                    write_u16(&mut b, 19, self.sectors() as u16);
+                    write_u16(&mut b, 22, self.fat_size() as u16);
+                },
+                FatType::Fat32 => {
+                    write_u32(&mut b, 32, self.sectors());
+                    write_u32(&mut b, 36, self.fat_size());
+                    write_u32(&mut b, 44, 2);
+                },
+            }
+            b
+        }
+
+        fn format(&self, name : &str) -> FileDisk {
+            let mut disk = FileDisk::create(name, self.start + self.sectors());
+            if self.start != 0 {
+                let mut mbr = [0; BLOCK_SIZE];
+                mbr[0x1C2] = 0x0C;
+                write_u32(&mut mbr, 0x1C6, self.start);
+                write_u32(&mut mbr, 0x1CA, self.sectors());
+                write_u16(&mut mbr, 510, 0xAA55);
+                disk.write_block(0, &mbr).unwrap();
+            }
+            disk.write_block(self.start, &self.boot_sector()).unwrap();
+
+            // media and reserved entries, the fat32 root directory in cluster 2
+            let mut fat = [0; BLOCK_SIZE];
+            match self.fat_type {
+                FatType::Fat16 => {
+                    write_u16(&mut fat, 0, 0xFFF8);
+                    write_u16(&mut fat, 2, 0xFFFF);
+                },
+                FatType::Fat32 => {
+                    write_u32(&mut fat, 0, 0x0FFF_FFF8);
+                    write_u32(&mut fat, 4, 0x0FFF_FFFF);
+                    write_u32(&mut fat, 8, 0x0FFF_FFFF);
+                },
+            }
+            for copy in 0..2 {
+                let lba = self.start + self.reserved + copy * self.fat_size();
+                disk.write_block(lba, &fat).unwrap();
+            }
+            disk
+        }
+    }
+
+    fn pattern(len : usize, seed : u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(7) ^ seed).collect()
+    }
+
+    fn read_all(vol : &mut FatVolume<FileDisk>, name : &str) -> Vec<u8> {
+        let entry = vol.find(name).unwrap();
+        let mut data = vec![0; entry.size as usize];
+        let mut done = 0;
+        while done < data.len() {
+            let n = vol.read(&entry, done as u32, &mut data[done..]).unwrap();
+            assert!(n > 0);
+            done += n;
+        }
+        data
+    }
+
+    fn free_clusters(vol : &mut FatVolume<FileDisk>) -> u32 {
+        let mut free = 0;
+        for cluster in 2..(vol.cluster_count + 2) {
+            if vol.fat_entry(cluster).unwrap() == 0 {
+                free += 1;
+            }
+        }
+        free
+    }
+
+    fn names(vol : &mut FatVolume<FileDisk>) -> Vec<[u8; 11]> {
+        let mut names = Vec::new();
+        vol.list(|e| names.push(e.name)).unwrap();
+        names
+    }
+
+    fn files(layout : &Layout, name : &str) {
+        let mut vol = FatVolume::mount(layout.format(name)).unwrap();
+        assert_eq!(vol.fat_type(), layout.fat_type);
+        let free = free_clusters(&mut vol);
+
+        let hello = pattern(1500, 1);
+        let notes = pattern(512, 2);
+        vol.write_file("hello.txt", &hello).unwrap();
+        vol.write_file("notes", &notes).unwrap();
+        vol.write_file("empty.bin", &[]).unwrap();
+        assert_eq!(free_clusters(&mut vol), free - 4);
+
+        // everything is on the disk, not in the volume
+        let mut vol = FatVolume::mount(vol.unmount()).unwrap();
+        assert_eq!(names(&mut vol), vec![*b"HELLO   TXT", *b"NOTES      ", *b"EMPTY   BIN"]);
+        assert_eq!(read_all(&mut vol, "HELLO.TXT"), hello);
+        assert_eq!(read_all(&mut vol, "notes"), notes);
+        assert_eq!(read_all(&mut vol, "empty.bin"), Vec::<u8>::new());
+
+        // reading from an offset inside the second sector
+        let entry = vol.find("hello.txt").unwrap();
+        let mut buf = [0; 100];
+        assert_eq!(vol.read(&entry, 1450, &mut buf).unwrap(), 50);
+        assert_eq!(&buf[..50], &hello[1450..]);
+
+        // replacing frees the old clusters
+        let shorter = pattern(700, 3);
+        vol.write_file("hello.txt", &shorter).unwrap();
+        assert_eq!(read_all(&mut vol, "hello.txt"), shorter);
+        assert_eq!(free_clusters(&mut vol), free - 3);
+
+        vol.delete("hello.txt").unwrap();
+        match vol.find("hello.txt") {
+            Err(FatError::NotFound) => {},
+            _ => panic!("deleted file found"),
+        }
+        assert_eq!(names(&mut vol), vec![*b"NOTES      ", *b"EMPTY   BIN"]);
+        assert_eq!(free_clusters(&mut vol), free - 1);
+    }
+
+    #[test]
+    fn fat16_files() {
+        files(&FAT16, "fat16_files");
+    }
+
+    #[test]
+    fn fat32_files() {
+        files(&FAT32, "fat32_files");
+    }
+
+    #[test]
+    fn full_disk_keeps_the_old_content() {
+        let disk = FAT16.format("full_disk");
+        let mut vol = FatVolume::mount(disk).unwrap();
+        // all but 8 clusters marked bad
+        for cluster in 10..(vol.cluster_count + 2) {
+            vol.set_fat_entry(cluster, 0xFFF7).unwrap();
+        }
+        let old = pattern(5 * BLOCK_SIZE, 4);
+        vol.write_file("data.bin", &old).unwrap();
+        assert_eq!(free_clusters(&mut vol), 3);
+
+        match vol.write_file("data.bin", &pattern(6 * BLOCK_SIZE, 5)) {
+            Err(FatError::DiskFull) => {},
+            _ => panic!("write larger than the free space accepted"),
+        }
+        assert_eq!(read_all(&mut vol, "data.bin"), old);
+        assert_eq!(free_clusters(&mut vol), 3);
+
+        // a new file isn't left behind empty
+        match vol.write_file("new.bin", &pattern(4 * BLOCK_SIZE, 6)) {
+            Err(FatError::DiskFull) => {},
+            _ => panic!("write larger than the free space accepted"),
+        }
+        match vol.find("new.bin") {
+            Err(FatError::NotFound) => {},
+            _ => panic!("failed file created"),
+        }
+        assert_eq!(names(&mut vol), vec![*b"DATA    BIN"]);
+        assert_eq!(free_clusters(&mut vol), 3);
+    }
+
+    // boot sectors written byte by byte and layouts computed by hand from the
+    // fat specification, so they don't share the assumptions of the module
+
+    // 20480 sectors, 4 per cluster, 4 reserved, 2 fats of 20 sectors, 512
+    // root entries : fats at 4 and 24, root at 44, data at 76, 5101 clusters
+    const FAT16_BPB : [u8; 64] = [
+        0xEB, 0x3C, 0x90, b'M', b'S', b'W', b'I', b'N', b'4', b'.', b'1',
+        0x00, 0x02, // bytes per sector
+        0x04, // sectors per cluster
+        0x04, 0x00, // reserved
+        0x02, // fats
+        0x00, 0x02, // root entries
+        0x00, 0x50, // total sectors
+        0xF8, // media
+        0x14, 0x00, // sectors per fat
+        0x20, 0x00, 0x40, 0x00, // geometry
+        0x00, 0x00, 0x00, 0x00, // hidden sectors
+        0x00, 0x00, 0x00, 0x00, // total sectors, 32 bits
+        0x80, 0x00, 0x29, 0x78, 0x56, 0x34, 0x12,
+        b'N', b'O', b' ', b'N', b'A', b'M', b'E', b' ', b' ', b' ', b' ',
+        b'F', b'A', b'T', b'1', b'6', b' ', b' ', b' ',
+        0x00, 0x00,
+    ];
+
+    // 67584 sectors, 1 per cluster, 32 reserved, 2 fats of 520 sectors, root
+    // directory in cluster 2 : fats at 32 and 552, data at 1072, 66512 clusters
+    const FAT32_BPB : [u8; 48] = [
+        0xEB, 0x58, 0x90, b'M', b'S', b'W', b'I', b'N', b'4', b'.', b'1',
+        0x00, 0x02, // bytes per sector
+        0x01, // sectors per cluster
+        0x20, 0x00, // reserved
+        0x02, // fats
+        0x00, 0x00, // root entries
+        0x00, 0x00, // total sectors
+        0xF8, // media
+        0x00, 0x00, // sectors per fat
+        0x20, 0x00, 0x40, 0x00, // geometry
+        0x00, 0x00, 0x00, 0x00, // hidden sectors
+        0x00, 0x08, 0x01, 0x00, // total sectors, 32 bits
+        0x08, 0x02, 0x00, 0x00, // sectors per fat, 32 bits
+        0x00, 0x00, 0x00, 0x00, // flags, version
+        0x02, 0x00, 0x00, 0x00, // root cluster
+    ];
+
+    fn bpb_image(name : &str, bpb : &[u8], sectors : u32, fats : &[u32], fat : &[u8]) -> FileDisk {
+        let mut disk = FileDisk::create(name, sectors);
+        let mut boot = [0; BLOCK_SIZE];
+        boot[..bpb.len()].copy_from_slice(bpb);
+        boot[510] = 0x55;
+        boot[511] = 0xAA;
+        disk.write_block(0, &boot).unwrap();
+        let mut first = [0; BLOCK_SIZE];
+        first[..fat.len()].copy_from_slice(fat);
+        for &lba in fats {
+            disk.write_block(lba, &first).unwrap();
+        }
+        disk
+    }
+
+    fn raw(disk : &mut FileDisk, offset : u64, len : usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        disk.file.seek(SeekFrom::Start(offset)).unwrap();
+        disk.file.read_exact(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn fat16_layout_from_spec() {
+        let disk = bpb_image("fat16_spec", &FAT16_BPB, 20480, &[4, 24], &[0xF8, 0xFF, 0xFF, 0xFF]);
+        let mut vol = FatVolume::mount(disk).unwrap();
+        assert_eq!(vol.fat_type(), FatType::Fat16);
+        assert_eq!((vol.fat_start, vol.root_dir_start, vol.data_start), (4, 44, 76));
+        assert_eq!(vol.cluster_count, 5101);
+
+        // two clusters of 2048 bytes, 2 and 3
+        let data = pattern(2100, 7);
+        vol.write_file("a.txt", &data).unwrap();
+        let mut disk = vol.unmount();
+
+        let entry = raw(&mut disk, 44 * 512, 32);
+        assert_eq!(&entry[..12], b"A       TXT\x20");
+        assert_eq!(&entry[26..32], &[0x02, 0x00, 0x34, 0x08, 0x00, 0x00]);
+        for &fat in [4u64, 24].iter() {
+            assert_eq!(raw(&mut disk, fat * 512 + 4, 4), vec![0x03, 0x00, 0xFF, 0xFF]);
+        }
+        assert_eq!(raw(&mut disk, 76 * 512, 2048), &data[..2048]);
+        assert_eq!(raw(&mut disk, 80 * 512, 52), &data[2048..]);
+    }
+
+    #[test]
+    fn fat32_layout_from_spec() {
+        let fat = [0xF8, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF, 0xFF, 0x0F];
+        let disk = bpb_image("fat32_spec", &FAT32_BPB, 67584, &[32, 552], &fat);
+        let mut vol = FatVolume::mount(disk).unwrap();
+        assert_eq!(vol.fat_type(), FatType::Fat32);
+        assert_eq!((vol.fat_start, vol.data_start, vol.root_cluster), (32, 1072, 2));
+        assert_eq!(vol.cluster_count, 66512);
+
+        // two clusters of 512 bytes after the root directory, 3 and 4
+        let data = pattern(600, 8);
+        vol.write_file("b.bin", &data).unwrap();
+        let mut disk = vol.unmount();
+
+        let entry = raw(&mut disk, 1072 * 512, 32);
+        assert_eq!(&entry[..12], b"B       BIN\x20");
+        assert_eq!(&entry[20..22], &[0x00, 0x00]);
+        assert_eq!(&entry[26..32], &[0x03, 0x00, 0x58, 0x02, 0x00, 0x00]);
+        for &fat in [32u64, 552].iter() {
+            assert_eq!(raw(&mut disk, fat * 512 + 12, 8), vec![0x04, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0x0F]);
+        }
+        assert_eq!(raw(&mut disk, 1073 * 512, 512), &data[..512]);
+        assert_eq!(raw(&mut disk, 1074 * 512, 88), &data[512..]);
+    }
+
+    #[test]
+    fn malformed_bpb_is_rejected() {
+        let mut disk = FAT16.format("malformed_bpb");
+        let mut boot = FAT16.boot_sector();
+        // fats larger than the volume
+        write_u16(&mut boot, 22, 0xFFFF);
+        disk.write_block(0, &boot).unwrap();
+        match FatVolume::mount(disk) {
+            Err(FatError::NoFilesystem) => {},
+            _ => panic!("malformed bpb mounted"),
+        }
+    }
+
+    #[test]
+    fn short_names() {
+        assert_eq!(short_name("readme.txt"), Some(*b"README  TXT"));
+        assert_eq!(short_name("A"), Some(*b"A          "));
+        assert_eq!(short_name("toolongname.txt"), None);
+        assert_eq!(short_name("bad name"), None);
+        assert_eq!(short_name(".txt"), None);
+    }
+}
@@ -0,0 +1,305 @@
+use spi::{Spi, SpiError, FreqPrescaler};
+use spi::bus::SpiDevice;
+
+mod block;
+pub mod fat;
+
+pub use self::block::{BlockDevice, BLOCK_SIZE};
+
+#[derive(Debug)]
+pub enum SdError {
+    Spi(SpiError),
+    Timeout,
+    NotIdle(u8),
+    UnsupportedCard,
+    CommandFailed(u8, u8),
+    ReadToken(u8),
+    WriteRejected(u8),
+    Crc,
+}
+
+impl From<SpiError> for SdError {
+    fn from(e : SpiError) -> SdError {
+        SdError::Spi(e)
+    }
+}
+
+type SdResult<T> = Result<T, SdError>;
+
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum CardType {
+    // byte addressed cards
+    SdV1,
+    SdV2,
+    // block addressed cards
+    Sdhc,
+}
+
+const CMD0 : u8 = 0;
+const CMD8 : u8 = 8;
+const CMD16 : u8 = 16;
+const CMD17 : u8 = 17;
+const CMD24 : u8 = 24;
+const CMD55 : u8 = 55;
+const CMD58 : u8 = 58;
+const CMD59 : u8 = 59;
+const ACMD41 : u8 = 41;
+
+const R1_IDLE : u8 = 0x01;
+const R1_ILLEGAL_COMMAND : u8 = 0x04;
+
+const DATA_TOKEN : u8 = 0xFE;
+const DATA_ACCEPTED : u8 = 0x05;
+
+const CMD_RETRIES : u32 = 16;
+const INIT_RETRIES : u32 = 10_000;
+const TOKEN_RETRIES : u32 = 100_000;
+
+pub struct SdCard<'a> {
+    dev : SpiDevice<'a>,
+    card_type : CardType,
+    crc : bool,
+}
+
+impl<'a> SdCard<'a> {
+    // crc enables the command and data crc checks by the card and on reads,
+    // speed is the prescaler used once the card is initialized
+    pub fn init(dev : SpiDevice<'a>, crc : bool, speed : FreqPrescaler) -> SdResult<SdCard<'a>> {
+        let mut card = SdCard {
+            dev : dev.prescaler(FreqPrescaler::Div256),
+            card_type : CardType::SdV1,
+            crc,
+        };
+
+        // at least 74 clocks with cs and mosi high to enter the native mode
+        card.dev.transaction_deselected(|spi| spi.write(&[0xFF; 10]))?;
+
+        let mut r1 = 0;
+        for _ in 0..CMD_RETRIES {
+            r1 = card.command(CMD0, 0)?;
+            if r1 == R1_IDLE {
+                break;
+            }
+        }
+        if r1 != R1_IDLE {
+            return Err(SdError::NotIdle(r1));
+        }
+
+        let mut r7 = [0xFF; 4];
+        let r1 = card.dev.transaction(|spi| {
+            let r1 = send_command(spi, CMD8, 0x1AA)?;
+            if r1 & R1_ILLEGAL_COMMAND == 0 {
+                spi.transfer(&mut r7)?;
+            }
+            Ok(r1)
+        })?;
+        let v2 = r1 & R1_ILLEGAL_COMMAND == 0;
+        if v2 && (r7[2] & 0x0F != 0x01 || r7[3] != 0xAA) {
+            return Err(SdError::UnsupportedCard);
+        }
+
+        if crc {
+            let r1 = card.command(CMD59, 1)?;
+            if r1 & !R1_IDLE != 0 {
+                return Err(SdError::CommandFailed(CMD59, r1));
+            }
+        }
+
+        // host capacity support is only announced to v2 cards
+        let hcs = if v2 { 1 << 30 } else { 0 };
+        let mut ready = false;
+        for _ in 0..INIT_RETRIES {
+            card.command(CMD55, 0)?;
+            let r1 = card.command(ACMD41, hcs)?;
+            if r1 == 0 {
+                ready = true;
+                break;
+            }
+            if r1 & !R1_IDLE != 0 {
+                return Err(SdError::CommandFailed(ACMD41, r1));
+            }
+        }
+        if !ready {
+            return Err(SdError::Timeout);
+        }
+
+        card.card_type = CardType::SdV1;
+        if v2 {
+            let mut ocr = [0xFF; 4];
+            let r1 = card.dev.transaction(|spi| {
+                let r1 = send_command(spi, CMD58, 0)?;
+                spi.transfer(&mut ocr)?;
+                Ok(r1)
+            })?;
+            if r1 != 0 {
+                return Err(SdError::CommandFailed(CMD58, r1));
+            }
+            card.card_type = if ocr[0] & 0x40 != 0 { CardType::Sdhc } else { CardType::SdV2 };
+        }
+
+        if card.card_type != CardType::Sdhc {
+            let r1 = card.command(CMD16, BLOCK_SIZE as u32)?;
+            if r1 != 0 {
+                return Err(SdError::CommandFailed(CMD16, r1));
+            }
+        }
+
+        card.dev.set_prescaler(speed);
+        Ok(card)
+    }
+
+    pub fn card_type(&self) -> CardType {
+        self.card_type
+    }
+
+    pub fn release(self) -> SpiDevice<'a> {
+        self.dev
+    }
+
+    fn command(&mut self, cmd : u8, arg : u32) -> SdResult<u8> {
+        let r1 = self.dev.transaction(|spi| send_command(spi, cmd, arg))?;
+        Ok(r1)
+    }
+
+    fn address(&self, lba : u32) -> u32 {
+        match self.card_type {
+            CardType::Sdhc => lba,
+            _ => lba * BLOCK_SIZE as u32,
+        }
+    }
+}
+
+impl<'a> BlockDevice for SdCard<'a> {
+    type Error = SdError;
+
+    fn read_block(&mut self, lba : u32, buf : &mut [u8; BLOCK_SIZE]) -> SdResult<()> {
+        let addr = self.address(lba);
+        let check_crc = self.crc;
+        let res = self.dev.transaction(|spi| Ok(read_data(spi, addr, buf, check_crc)))?;
+        res
+    }
+
+    fn write_block(&mut self, lba : u32, buf : &[u8; BLOCK_SIZE]) -> SdResult<()> {
+        let addr = self.address(lba);
+        let res = self.dev.transaction(|spi| Ok(write_data(spi, addr, buf)))?;
+        res
+    }
+}
+
+fn read_data(spi : &mut Spi, addr : u32, buf : &mut [u8; BLOCK_SIZE], check_crc : bool) -> SdResult<()> {
+    let r1 = send_command(spi, CMD17, addr)?;
+    if r1 != 0 {
+        return Err(SdError::CommandFailed(CMD17, r1));
+    }
+
+    let mut token = [0xFF];
+    for _ in 0..TOKEN_RETRIES {
+        token[0] = 0xFF;
+        spi.transfer(&mut token)?;
+        if token[0] != 0xFF {
+            break;
+        }
+    }
+    if token[0] != DATA_TOKEN {
+        return Err(SdError::ReadToken(token[0]));
+    }
+
+    for b in buf.iter_mut() {
+        *b = 0xFF;
+    }
+    spi.transfer(buf)?;
+    let mut crc = [0xFF; 2];
+    spi.transfer(&mut crc)?;
+
+    if check_crc && crc16(buf) != ((crc[0] as u16) << 8 | crc[1] as u16) {
+        return Err(SdError::Crc);
+    }
+    Ok(())
+}
+
+fn write_data(spi : &mut Spi, addr : u32, buf : &[u8; BLOCK_SIZE]) -> SdResult<()> {
+    let r1 = send_command(spi, CMD24, addr)?;
+    if r1 != 0 {
+        return Err(SdError::CommandFailed(CMD24, r1));
+    }
+
+    let crc = crc16(buf);
+    spi.write(&[0xFF, DATA_TOKEN])?;
+    spi.write(buf)?;
+    spi.write(&[(crc >> 8) as u8, crc as u8])?;
+
+    let mut resp = [0xFF];
+    spi.transfer(&mut resp)?;
+    if resp[0] & 0x1F != DATA_ACCEPTED {
+        return Err(SdError::WriteRejected(resp[0]));
+    }
+
+    // the card holds miso low while programming
+    for _ in 0..TOKEN_RETRIES {
+        resp[0] = 0xFF;
+        spi.transfer(&mut resp)?;
+        if resp[0] == 0xFF {
+            return Ok(());
+        }
+    }
+    Err(SdError::Timeout)
+}
+
+// returns the r1 byte, any extra response bytes are left to the caller
+fn send_command(spi : &mut Spi, cmd : u8, arg : u32) -> SpiResult<u8> {
+    let mut frame = [
+        0x40 | cmd,
+        (arg >> 24) as u8,
+        (arg >> 16) as u8,
+        (arg >> 8) as u8,
+        arg as u8,
+        0,
+    ];
+    frame[5] = (crc7(&frame[..5]) << 1) | 1;
+
+    spi.write(&[0xFF])?;
+    spi.write(&frame)?;
+
+    let mut r1 = [0xFF];
+    for _ in 0..CMD_RETRIES {
+        r1[0] = 0xFF;
+        spi.transfer(&mut r1)?;
+        if r1[0] & 0x80 == 0 {
+            break;
+        }
+    }
+    Ok(r1[0])
+}
+
+type SpiResult<T> = Result<T, SpiError>;
+
+pub fn crc7(data : &[u8]) -> u8 {
+    let mut crc : u8 = 0;
+    for &b in data {
+        let mut d = b;
+        for _ in 0..8 {
+            crc <<= 1;
+            if (d ^ crc) & 0x80 != 0 {
+                crc ^= 0x09;
+            }
+            d <<= 1;
+        }
+    }
+    crc & 0x7F
+}
+
+// ccitt polynomial 0x1021, initial value 0
+pub fn crc16(data : &[u8]) -> u16 {
+    let mut crc : u16 = 0;
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
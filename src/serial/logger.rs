@@ -0,0 +1,72 @@
+use core::fmt;
+
+use hal::serial::Write;
+
+use serial::Serial;
+
+pub const LOG_BUFFER_LEN : usize = 256;
+
+// formatted output is queued in a ring buffer and sent by `poll`, bytes that
+// don't fit are dropped and counted instead of stalling the caller
+pub struct Logger {
+    serial : Serial,
+    buffer : [u8; LOG_BUFFER_LEN],
+    head : usize,
+    count : usize,
+    dropped : u32,
+}
+
+impl Logger {
+    pub fn new(serial : Serial) -> Logger {
+        Logger {
+            serial,
+            buffer : [0; LOG_BUFFER_LEN],
+            head : 0,
+            count : 0,
+            dropped : 0,
+        }
+    }
+
+    // send as many queued bytes as the usart accepts without waiting
+    pub fn poll(&mut self) {
+        while self.count > 0 {
+            let tail = (self.head + LOG_BUFFER_LEN - self.count) % LOG_BUFFER_LEN;
+            if self.serial.write(self.buffer[tail]).is_err() {
+                break;
+            }
+            self.count -= 1;
+        }
+    }
+
+    pub fn pending(&self) -> usize {
+        self.count
+    }
+
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    pub fn clear_dropped(&mut self) {
+        self.dropped = 0;
+    }
+
+    pub fn release(self) -> Serial {
+        self.serial
+    }
+}
+
+impl fmt::Write for Logger {
+    fn write_str(&mut self, s: &str) -> Result<(), fmt::Error> {
+        for &b in s.as_bytes() {
+            if self.count == LOG_BUFFER_LEN {
+                self.dropped = self.dropped.wrapping_add(1);
+                continue;
+            }
+            self.buffer[self.head] = b;
+            self.head = (self.head + 1) % LOG_BUFFER_LEN;
+            self.count += 1;
+        }
+        self.poll();
+        Ok(())
+    }
+}
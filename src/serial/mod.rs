@@ -4,8 +4,24 @@ use cortex_m::interrupt;
 
 use core::fmt;
 
+use hal;
+use nb;
+
 use clocks::*;
 
+pub mod logger;
+pub mod rs485;
+
+#[derive(Debug)]
+pub enum SerialError {
+    Overrun,
+    Noise,
+    Framing,
+    Parity,
+    InvalidAddress,
+    DriverEnable,
+}
+
 pub struct Serial;
 
 #[derive(Copy, Clone)]
@@ -34,10 +50,49 @@ pub enum StopBits {
     Stop15,
 }
 
+#[derive(Copy, Clone)]
+pub enum LinBreakLength {
+    Bits10,
+    Bits11,
+}
+
+#[derive(Copy, Clone)]
+pub enum IrdaPower {
+    Normal,
+    LowPower,
+}
+
+#[derive(Copy, Clone)]
+pub enum SerialMode {
+    Normal,
+    // single wire on the tx pin, for servo busses
+    HalfDuplex,
+    // 8 data bits, 1 stop bit, break generation and detection
+    Lin(LinBreakLength),
+    IrDa(IrdaPower),
+}
+
+#[derive(Copy, Clone)]
+pub enum ClockPolarity {
+    IdleLow,
+    IdleHigh,
+}
+
+#[derive(Copy, Clone)]
+pub enum ClockPhase {
+    FirstEdge,
+    SecondEdge,
+}
+
 pub struct SerialConfig {
     baud_rate : Option<BaudRate>,
     data_length : Option<DataLength>,
     stop_bits : Option<StopBits>,
+    mode : SerialMode,
+
+    clk_pol : ClockPolarity,
+    clk_pha : ClockPhase,
+    last_bit_clock : bool,
 }
 
 impl SerialConfig {
@@ -46,6 +101,10 @@ impl SerialConfig {
             baud_rate : None,
             data_length : None,
             stop_bits : None,
+            mode : SerialMode::Normal,
+            clk_pol : ClockPolarity::IdleLow,
+            clk_pha : ClockPhase::FirstEdge,
+            last_bit_clock : false,
         }
     }
 
@@ -64,6 +123,69 @@ impl SerialConfig {
         self
     }
 
+    pub fn mode(mut self, mode : SerialMode) -> SerialConfig {
+        self.mode = mode;
+        self
+    }
+
+    pub fn clock_polarity(mut self, cp : ClockPolarity) -> SerialConfig {
+        self.clk_pol = cp;
+        self
+    }
+
+    pub fn clock_phase(mut self, cp : ClockPhase) -> SerialConfig {
+        self.clk_pha = cp;
+        self
+    }
+
+    pub fn last_bit_clock(mut self, en : bool) -> SerialConfig {
+        self.last_bit_clock = en;
+        self
+    }
+
+    // master clock on the CK pin (PA4), the usart sends the lsb first
+    pub fn configure_synchronous(mut self) -> SyncSerial {
+        self.mode = SerialMode::Normal;
+        let clk_pol = self.clk_pol;
+        let clk_pha = self.clk_pha;
+        let last_bit_clock = self.last_bit_clock;
+        self.configure();
+
+        interrupt::free(|cs| {
+            let gpio = GPIOA.borrow(cs);
+            let uart = USART2.borrow(cs);
+
+            gpio.crl.modify(|_, w| {
+                w.cnf4().bits(0b10)
+                .mode4().bits(0b11)
+            });
+
+            // clock settings can only be changed with the transmitter and receiver disabled
+            uart.cr1.modify(|_, w| {
+                w.te().bit(false)
+                    .re().bit(false)
+            });
+            uart.cr2.modify(|_, w| {
+                w.cpol().bit(match clk_pol {
+                    ClockPolarity::IdleLow => false,
+                    ClockPolarity::IdleHigh => true,
+                })
+                .cpha().bit(match clk_pha {
+                    ClockPhase::FirstEdge => false,
+                    ClockPhase::SecondEdge => true,
+                })
+                .lbcl().bit(last_bit_clock)
+                .clken().bit(true)
+            });
+            uart.cr1.modify(|_, w| {
+                w.te().bit(true)
+                    .re().bit(true)
+            });
+        });
+
+        SyncSerial
+    }
+
     pub fn configure(self) -> Serial {
         interrupt::free(|cs| {
             let rcc = RCC.borrow(cs);
@@ -127,8 +249,58 @@ impl SerialConfig {
                 });
             }
 
+            // every mode starts from a plain asynchronous configuration
+            uart.cr2.modify(|_, w| {
+                w.linen().bit(false)
+                    .clken().bit(false)
+            });
+            uart.cr3.modify(|_, w| {
+                w.hdsel().bit(false)
+                    .iren().bit(false)
+                    .scen().bit(false)
+            });
+
+            match self.mode {
+                SerialMode::Normal => {},
+                SerialMode::HalfDuplex => {
+                    // tx pin as open drain, the other nodes pull the line low when answering
+                    gpio.crl.modify(|_, w| {
+                        w.cnf2().bits(0b11)
+                        .mode2().bits(0b11)
+                    });
+                    uart.cr3.modify(|_, w| w.hdsel().bit(true));
+                },
+                SerialMode::Lin(bl) => {
+                    uart.cr1.modify(|_, w| w.m().bit(false));
+                    uart.cr2.modify(|_, w| unsafe {
+                        w.stop().bits(0b00)
+                            .lbdl().bit(match bl {
+                                LinBreakLength::Bits10 => false,
+                                LinBreakLength::Bits11 => true,
+                            })
+                            .linen().bit(true)
+                    });
+                },
+                SerialMode::IrDa(power) => {
+                    match power {
+                        IrdaPower::Normal => {
+                            uart.gtpr.modify(|_, w| unsafe { w.psc().bits(1) });
+                            uart.cr3.modify(|_, w| w.irlp().bit(false));
+                        },
+                        IrdaPower::LowPower => {
+                            // the low power pulses are based on a ~1.8432MHz clock
+                            let psc = ClockConfig::get_speeds().apb1_clk / 1_843_200;
+                            uart.gtpr.modify(|_, w| unsafe { w.psc().bits(psc as u8) });
+                            uart.cr3.modify(|_, w| w.irlp().bit(true));
+                        },
+                    }
+                    uart.cr3.modify(|_, w| w.iren().bit(true));
+                },
+            }
+
             uart.cr1.modify(|_, w| {
                 w.te().bit(true)
+                    .re().bit(true)
                     .ue().bit(true)
             });
         });
@@ -141,16 +313,148 @@ impl SerialConfig {
     }
 }
 
+impl Serial {
+    pub fn read_byte(&mut self) -> Option<u8> {
+        hal::serial::Read::read(self).ok()
+    }
+
+    pub fn write_byte(&mut self, b : u8) {
+        block!(hal::serial::Write::write(self, b)).ok();
+    }
+
+    // lin mode only, the break is sent after the current character
+    pub fn send_break(&mut self) {
+        unsafe {
+            (*USART2.get()).cr1.modify(|_, w| w.sbk().bit(true));
+            while (*USART2.get()).cr1.read().sbk().bit() {}
+        }
+    }
+
+    // lin mode only, clears the flag when a break was detected
+    pub fn break_detected(&mut self) -> bool {
+        unsafe {
+            if (*USART2.get()).sr.read().lbd().bit() {
+                (*USART2.get()).sr.modify(|_, w| w.lbd().bit(false));
+                true
+            } else {
+                false
+            }
+        }
+    }
+
+    // break, sync field and protected identifier of a lin master frame
+    pub fn send_lin_header(&mut self, id : u8) {
+        let id = id & 0x3F;
+        let bit = |n : u8| (id >> n) & 1;
+        let p0 = bit(0) ^ bit(1) ^ bit(2) ^ bit(4);
+        let p1 = !(bit(1) ^ bit(3) ^ bit(4) ^ bit(5)) & 1;
+
+        self.send_break();
+        self.write_byte(0x55);
+        self.write_byte(id | (p0 << 6) | (p1 << 7));
+    }
+}
+
+impl hal::serial::Read<u8> for Serial {
+    type Error = SerialError;
+
+    fn read(&mut self) -> nb::Result<u8, SerialError> {
+        unsafe {
+            let sr = (*USART2.get()).sr.read();
+            // error flags are cleared by reading sr then dr
+            let err = if sr.ore().bit() {
+                Some(SerialError::Overrun)
+            } else if sr.fe().bit() {
+                Some(SerialError::Framing)
+            } else if sr.ne().bit() {
+                Some(SerialError::Noise)
+            } else if sr.pe().bit() {
+                Some(SerialError::Parity)
+            } else {
+                None
+            };
+
+            if let Some(e) = err {
+                (*USART2.get()).dr.read();
+                return Err(nb::Error::Other(e));
+            }
+
+            if sr.rxne().bit() {
+                Ok(((*USART2.get()).dr.read().bits() & 0x000000FF) as u8)
+            } else {
+                Err(nb::Error::WouldBlock)
+            }
+        }
+    }
+}
+
+impl hal::serial::Write<u8> for Serial {
+    type Error = SerialError;
+
+    fn write(&mut self, b : u8) -> nb::Result<(), SerialError> {
+        unsafe {
+            if (*USART2.get()).sr.read().txe().bit() {
+                (*USART2.get()).dr.write(|w| w.bits(b as u32));
+                Ok(())
+            } else {
+                Err(nb::Error::WouldBlock)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> nb::Result<(), SerialError> {
+        unsafe {
+            if (*USART2.get()).sr.read().tc().bit() {
+                Ok(())
+            } else {
+                Err(nb::Error::WouldBlock)
+            }
+        }
+    }
+}
+
+impl hal::blocking::serial::write::Default<u8> for Serial {}
 
 impl fmt::Write for Serial {
     fn write_str(&mut self, s: &str) -> Result<(), fmt::Error> {
-        unsafe{
-            for c in s.chars() {
+        // send the utf-8 encoding byte by byte, chars above 0xFF can't be truncated to a byte
+        for b in s.bytes() {
+            block!(hal::serial::Write::write(self, b)).map_err(|_| fmt::Error)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct SyncSerial;
+
+impl SyncSerial {
+    // each byte of buf is sent and replaced by the byte received at the same time
+    pub fn transfer(&mut self, buf : &mut [u8]) -> Result<(), SerialError> {
+        unsafe {
+            for b in buf.iter_mut() {
                 while !(*USART2.get()).sr.read().txe().bit() {}
-                (*USART2.get()).dr.write(|w| unsafe {
-                    w.bits((c as u32) & 0x000000FF)
-                });
+                (*USART2.get()).dr.write(|w| w.bits(*b as u32));
+                loop {
+                    let sr = (*USART2.get()).sr.read();
+                    if sr.ore().bit() {
+                        (*USART2.get()).dr.read();
+                        return Err(SerialError::Overrun);
+                    }
+                    if sr.rxne().bit() {
+                        break;
+                    }
+                }
+                *b = ((*USART2.get()).dr.read().bits() & 0x000000FF) as u8;
             }
+            while !(*USART2.get()).sr.read().tc().bit() {}
+        }
+        Ok(())
+    }
+
+    pub fn write(&mut self, buf : &[u8]) -> Result<(), SerialError> {
+        for &b in buf {
+            let mut word = [b];
+            self.transfer(&mut word)?;
         }
         Ok(())
     }
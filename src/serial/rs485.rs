@@ -0,0 +1,113 @@
+use stm32f103xx::USART2;
+
+use nb;
+
+use gpio::{Gpio, State};
+use serial::{Serial, SerialError};
+
+pub const MAX_ADDRESS : u8 = 0x0F;
+
+const ADDRESS_MARK : u32 = 0x100;
+
+#[derive(Eq, PartialEq, Copy, Clone)]
+pub enum Wakeup {
+    IdleLine,
+    // 9 bits frames, the 9th bit flags an address character
+    AddressMark,
+}
+
+pub struct Rs485 {
+    serial : Serial,
+    de : Gpio,
+    address : u8,
+    wakeup : Wakeup,
+}
+
+impl Rs485 {
+    // de is the driver enable pin of the transceiver, configured as an output
+    pub fn new(serial : Serial, de : Gpio, address : u8, wakeup : Wakeup) -> Result<Rs485, SerialError> {
+        if address > MAX_ADDRESS {
+            return Err(SerialError::InvalidAddress);
+        }
+        de.set(State::Low).map_err(|_| SerialError::DriverEnable)?;
+
+        unsafe {
+            let uart = &*USART2.get();
+            uart.cr1.modify(|_, w| w.ue().bit(false));
+            uart.cr1.modify(|_, w| {
+                w.m().bit(wakeup == Wakeup::AddressMark)
+                    .wake().bit(wakeup == Wakeup::AddressMark)
+            });
+            uart.cr2.modify(|_, w| w.add().bits(address));
+            uart.cr1.modify(|_, w| w.ue().bit(true));
+        }
+
+        Ok(Rs485 {
+            serial,
+            de,
+            address,
+            wakeup,
+        })
+    }
+
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    // the receiver ignores the bus until the next idle line or an address mark for this node
+    pub fn mute(&mut self) {
+        unsafe {
+            (*USART2.get()).cr1.modify(|_, w| w.rwu().bit(true));
+        }
+    }
+
+    pub fn is_muted(&self) -> bool {
+        unsafe { (*USART2.get()).cr1.read().rwu().bit() }
+    }
+
+    // with idle line wakeup the address is not sent, every node wakes up
+    pub fn send_to(&mut self, address : u8, data : &[u8]) -> Result<(), SerialError> {
+        if address > MAX_ADDRESS {
+            return Err(SerialError::InvalidAddress);
+        }
+        self.de.set(State::High).map_err(|_| SerialError::DriverEnable)?;
+        unsafe {
+            let uart = &*USART2.get();
+            if self.wakeup == Wakeup::AddressMark {
+                while !uart.sr.read().txe().bit() {}
+                uart.dr.write(|w| w.bits(ADDRESS_MARK | address as u32));
+            }
+            for &b in data {
+                while !uart.sr.read().txe().bit() {}
+                uart.dr.write(|w| w.bits(b as u32));
+            }
+            // release the bus only once the last stop bit is out
+            while !uart.sr.read().tc().bit() {}
+        }
+        self.de.set(State::Low).map_err(|_| SerialError::DriverEnable)
+    }
+
+    pub fn read(&mut self) -> nb::Result<u8, SerialError> {
+        unsafe {
+            let uart = &*USART2.get();
+            let sr = uart.sr.read();
+            if sr.ore().bit() || sr.fe().bit() || sr.ne().bit() || sr.pe().bit() {
+                // same error decoding as the plain serial port
+                return ::hal::serial::Read::read(&mut self.serial);
+            }
+            if !sr.rxne().bit() {
+                return Err(nb::Error::WouldBlock);
+            }
+            let word = uart.dr.read().bits();
+            // the address character that woke the receiver up is not data
+            if self.wakeup == Wakeup::AddressMark && word & ADDRESS_MARK != 0 {
+                return Err(nb::Error::WouldBlock);
+            }
+            Ok((word & 0x000000FF) as u8)
+        }
+    }
+
+    pub fn release(self) -> (Serial, Gpio) {
+        (self.serial, self.de)
+    }
+}
@@ -0,0 +1,434 @@
+use core::fmt::Write;
+use core::ptr;
+use core::str;
+
+use stm32f103xx::{GPIOA, GPIOB, GPIOC};
+use clocks::*;
+use gpio::*;
+use serial::Serial;
+
+#[derive(Debug)]
+pub enum ShellError {
+    CommandTableFull,
+    CommandExists,
+    UnknownCommand,
+    BadArguments,
+    CommandFailed,
+}
+
+pub type ShellResult<T> = Result<T, ShellError>;
+
+pub type CommandFn = fn(&mut Serial, &[&str]) -> ShellResult<()>;
+
+pub const LINE_LEN : usize = 64;
+pub const HISTORY_LEN : usize = 4;
+pub const MAX_COMMANDS : usize = 16;
+pub const MAX_ARGS : usize = 8;
+
+const PROMPT : &'static str = "> ";
+
+const BACKSPACE : u8 = 0x08;
+const DELETE : u8 = 0x7F;
+const TAB : u8 = 0x09;
+const ESCAPE : u8 = 0x1B;
+const CTRL_C : u8 = 0x03;
+
+#[derive(Copy, Clone)]
+pub struct Command {
+    name : &'static str,
+    help : &'static str,
+    func : CommandFn,
+}
+
+#[derive(Eq, PartialEq, Copy, Clone)]
+enum EscState {
+    Idle,
+    Escape,
+    Bracket,
+}
+
+pub struct Shell {
+    serial : Serial,
+    commands : [Option<Command>; MAX_COMMANDS],
+
+    line : [u8; LINE_LEN],
+    len : usize,
+
+    history : [[u8; LINE_LEN]; HISTORY_LEN],
+    history_lens : [usize; HISTORY_LEN],
+    history_count : usize,
+    history_head : usize,
+    history_pos : Option<usize>,
+
+    esc : EscState,
+}
+
+impl Shell {
+    pub fn new(serial : Serial) -> Shell {
+        let mut shell = Shell {
+            serial,
+            commands : [None; MAX_COMMANDS],
+            line : [0; LINE_LEN],
+            len : 0,
+            history : [[0; LINE_LEN]; HISTORY_LEN],
+            history_lens : [0; HISTORY_LEN],
+            history_count : 0,
+            history_head : 0,
+            history_pos : None,
+            esc : EscState::Idle,
+        };
+
+        // the table is empty at this point, the built-ins always fit
+        shell.register("clocks", "print the bus frequencies", cmd_clocks).ok();
+        shell.register("gpio", "gpio in <port> <pin> | gpio out <port> <pin> <0|1>", cmd_gpio).ok();
+        shell.register("peek", "peek <addr> : read a 32 bits register", cmd_peek).ok();
+        shell.register("poke", "poke <addr> <value> : write a 32 bits register", cmd_poke).ok();
+        shell
+    }
+
+    pub fn register(&mut self, name : &'static str, help : &'static str, func : CommandFn) -> ShellResult<()> {
+        if name == "help" || self.find(name).is_some() {
+            return Err(ShellError::CommandExists);
+        }
+        for slot in self.commands.iter_mut() {
+            if slot.is_none() {
+                *slot = Some(Command { name, help, func });
+                return Ok(());
+            }
+        }
+        Err(ShellError::CommandTableFull)
+    }
+
+    pub fn run(&mut self) -> ! {
+        self.prompt();
+        loop {
+            if let Some(b) = self.serial.read_byte() {
+                self.feed(b);
+            }
+        }
+    }
+
+    // can also be called from the usart interrupt with each received byte
+    pub fn feed(&mut self, b : u8) {
+        match self.esc {
+            EscState::Escape => {
+                self.esc = if b == b'[' { EscState::Bracket } else { EscState::Idle };
+                return;
+            },
+            EscState::Bracket => {
+                self.esc = EscState::Idle;
+                match b {
+                    b'A' => self.history_up(),
+                    b'B' => self.history_down(),
+                    _ => {},
+                }
+                return;
+            },
+            EscState::Idle => {},
+        }
+
+        match b {
+            b'\r' | b'\n' => {
+                write!(self.serial, "\r\n").ok();
+                self.execute();
+                self.prompt();
+            },
+            BACKSPACE | DELETE => {
+                if self.len > 0 {
+                    self.len -= 1;
+                    write!(self.serial, "\x08 \x08").ok();
+                }
+            },
+            TAB => self.complete(),
+            ESCAPE => self.esc = EscState::Escape,
+            CTRL_C => {
+                self.len = 0;
+                self.history_pos = None;
+                write!(self.serial, "^C\r\n").ok();
+                self.prompt();
+            },
+            0x20...0x7E => {
+                if self.len < LINE_LEN {
+                    self.line[self.len] = b;
+                    self.len += 1;
+                    self.serial.write_byte(b);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn prompt(&mut self) {
+        write!(self.serial, "{}", PROMPT).ok();
+    }
+
+    fn find(&self, name : &str) -> Option<Command> {
+        for c in self.commands.iter() {
+            if let Some(cmd) = *c {
+                if cmd.name == name {
+                    return Some(cmd);
+                }
+            }
+        }
+        None
+    }
+
+    fn execute(&mut self) {
+        let len = self.len;
+        let line = self.line;
+        self.len = 0;
+        self.history_pos = None;
+
+        if len == 0 {
+            return;
+        }
+        self.push_history(&line[..len]);
+
+        // only printable ascii is accepted in the line buffer
+        let text = unsafe { str::from_utf8_unchecked(&line[..len]) };
+        let mut args : [&str; MAX_ARGS] = [""; MAX_ARGS];
+        let mut argc = 0;
+        for word in text.split_whitespace() {
+            if argc == MAX_ARGS {
+                write!(self.serial, "too many arguments\r\n").ok();
+                return;
+            }
+            args[argc] = word;
+            argc += 1;
+        }
+        if argc == 0 {
+            return;
+        }
+
+        if args[0] == "help" {
+            self.help();
+            return;
+        }
+
+        let res = match self.find(args[0]) {
+            Some(cmd) => (cmd.func)(&mut self.serial, &args[1..argc]),
+            None => Err(ShellError::UnknownCommand),
+        };
+        if let Err(e) = res {
+            write!(self.serial, "error : {:?}\r\n", e).ok();
+        }
+    }
+
+    fn help(&mut self) {
+        write!(self.serial, "help\r\n").ok();
+        for c in self.commands.iter() {
+            if let Some(cmd) = *c {
+                write!(self.serial, "{} : {}\r\n", cmd.name, cmd.help).ok();
+            }
+        }
+    }
+
+    fn complete(&mut self) {
+        let len = self.len;
+        let line = self.line;
+        // only the command name is completed
+        if line[..len].contains(&b' ') {
+            return;
+        }
+        let prefix = unsafe { str::from_utf8_unchecked(&line[..len]) };
+
+        let mut matches = 0;
+        let mut last = "";
+        if "help".starts_with(prefix) {
+            matches += 1;
+            last = "help";
+        }
+        for c in self.commands.iter() {
+            if let Some(cmd) = *c {
+                if cmd.name.starts_with(prefix) {
+                    matches += 1;
+                    last = cmd.name;
+                }
+            }
+        }
+
+        if matches == 1 {
+            for &b in last.as_bytes()[len..].iter().chain(b" ".iter()) {
+                if self.len < LINE_LEN {
+                    self.line[self.len] = b;
+                    self.len += 1;
+                    self.serial.write_byte(b);
+                }
+            }
+        } else if matches > 1 {
+            write!(self.serial, "\r\n").ok();
+            if "help".starts_with(prefix) {
+                write!(self.serial, "help ").ok();
+            }
+            for c in self.commands.iter() {
+                if let Some(cmd) = *c {
+                    if cmd.name.starts_with(prefix) {
+                        write!(self.serial, "{} ", cmd.name).ok();
+                    }
+                }
+            }
+            write!(self.serial, "\r\n").ok();
+            self.prompt();
+            for &b in line[..len].iter() {
+                self.serial.write_byte(b);
+            }
+        }
+    }
+
+    fn push_history(&mut self, entry : &[u8]) {
+        self.history[self.history_head][..entry.len()].copy_from_slice(entry);
+        self.history_lens[self.history_head] = entry.len();
+        self.history_head = (self.history_head + 1) % HISTORY_LEN;
+        if self.history_count < HISTORY_LEN {
+            self.history_count += 1;
+        }
+    }
+
+    fn history_up(&mut self) {
+        let pos = match self.history_pos {
+            Some(p) if p + 1 < self.history_count => p + 1,
+            Some(p) => p,
+            None if self.history_count > 0 => 0,
+            None => return,
+        };
+        self.history_pos = Some(pos);
+        self.recall(pos);
+    }
+
+    fn history_down(&mut self) {
+        match self.history_pos {
+            Some(0) => {
+                self.history_pos = None;
+                self.replace_line(&[]);
+            },
+            Some(p) => {
+                self.history_pos = Some(p - 1);
+                self.recall(p - 1);
+            },
+            None => {},
+        }
+    }
+
+    // pos 0 is the most recent entry
+    fn recall(&mut self, pos : usize) {
+        let idx = (self.history_head + HISTORY_LEN - 1 - pos) % HISTORY_LEN;
+        let entry = self.history[idx];
+        let len = self.history_lens[idx];
+        self.replace_line(&entry[..len]);
+    }
+
+    fn replace_line(&mut self, content : &[u8]) {
+        for _ in 0..self.len {
+            write!(self.serial, "\x08 \x08").ok();
+        }
+        self.line[..content.len()].copy_from_slice(content);
+        self.len = content.len();
+        for &b in content.iter() {
+            self.serial.write_byte(b);
+        }
+    }
+}
+
+fn parse_u32(s : &str) -> ShellResult<u32> {
+    let res = if s.starts_with("0x") || s.starts_with("0X") {
+        u32::from_str_radix(&s[2..], 16)
+    } else {
+        u32::from_str_radix(s, 10)
+    };
+    res.map_err(|_| ShellError::BadArguments)
+}
+
+fn parse_port(s : &str) -> ShellResult<Port> {
+    match s {
+        "a" | "A" => Ok(Port::A),
+        "b" | "B" => Ok(Port::B),
+        "c" | "C" => Ok(Port::C),
+        _ => Err(ShellError::BadArguments),
+    }
+}
+
+fn parse_pin(s : &str) -> ShellResult<Pin> {
+    let n = parse_u32(s)?;
+    if n > 15 {
+        return Err(ShellError::BadArguments);
+    }
+    Ok(Pin(n))
+}
+
+fn cmd_clocks(ser : &mut Serial, _args : &[&str]) -> ShellResult<()> {
+    let speeds = ClockConfig::get_speeds();
+    write!(ser, "sysclk : {} Hz\r\n", speeds.sys_clk).ok();
+    write!(ser, "ahb    : {} Hz\r\n", speeds.ahb_clk).ok();
+    write!(ser, "apb1   : {} Hz\r\n", speeds.apb1_clk).ok();
+    write!(ser, "apb2   : {} Hz\r\n", speeds.apb2_clk).ok();
+    Ok(())
+}
+
+fn cmd_gpio(ser : &mut Serial, args : &[&str]) -> ShellResult<()> {
+    if args.len() < 3 {
+        return Err(ShellError::BadArguments);
+    }
+    let port = parse_port(args[1])?;
+    let pin = parse_pin(args[2])?;
+
+    match args[0] {
+        "in" => {
+            // IDR reflects the pin in any mode, leave its configuration alone
+            let idr = unsafe {
+                match port {
+                    Port::A => (*GPIOA.get()).idr.read().bits(),
+                    Port::B => (*GPIOB.get()).idr.read().bits(),
+                    Port::C => (*GPIOC.get()).idr.read().bits(),
+                }
+            };
+            if idr & (pin.code() as u32) != 0 {
+                write!(ser, "1\r\n").ok();
+            } else {
+                write!(ser, "0\r\n").ok();
+            }
+            Ok(())
+        },
+        "out" if args.len() == 4 => {
+            let state = match args[3] {
+                "0" => State::Low,
+                "1" => State::High,
+                _ => return Err(ShellError::BadArguments),
+            };
+            let gpio = GpioConfig::new()
+                .port(port)
+                .pin(pin)
+                .conf(Conf::PushPullOut)
+                .mode(Mode::Output2MHz)
+                .configure()
+                .map_err(|_| ShellError::CommandFailed)?;
+            gpio.set(state).map_err(|_| ShellError::CommandFailed)
+        },
+        _ => Err(ShellError::BadArguments),
+    }
+}
+
+fn cmd_peek(ser : &mut Serial, args : &[&str]) -> ShellResult<()> {
+    if args.len() != 1 {
+        return Err(ShellError::BadArguments);
+    }
+    let addr = parse_u32(args[0])?;
+    if addr & 0b11 != 0 {
+        return Err(ShellError::BadArguments);
+    }
+    let val = unsafe { ptr::read_volatile(addr as *const u32) };
+    write!(ser, "0x{:08x} : 0x{:08x}\r\n", addr, val).ok();
+    Ok(())
+}
+
+fn cmd_poke(_ser : &mut Serial, args : &[&str]) -> ShellResult<()> {
+    if args.len() != 2 {
+        return Err(ShellError::BadArguments);
+    }
+    let addr = parse_u32(args[0])?;
+    let val = parse_u32(args[1])?;
+    if addr & 0b11 != 0 {
+        return Err(ShellError::BadArguments);
+    }
+    unsafe { ptr::write_volatile(addr as *mut u32, val) };
+    Ok(())
+}
@@ -0,0 +1,136 @@
+use core::cell::RefCell;
+
+use hal;
+
+use gpio::{Gpio, State};
+use nor_flash;
+use spi::*;
+
+// several devices on the same spi, each with its own chip select and clock settings
+pub struct SpiBus {
+    spi : RefCell<Spi>,
+}
+
+impl SpiBus {
+    pub fn new(spi : Spi) -> SpiBus {
+        SpiBus {
+            spi : RefCell::new(spi),
+        }
+    }
+
+    // cs must be configured as an output, it is released right away
+    pub fn device(&self, cs : Gpio) -> SpiResult<SpiDevice> {
+        cs.set(State::High).map_err(|_| SpiError::ChipSelect)?;
+        Ok(SpiDevice {
+            bus : &self.spi,
+            cs,
+            clk_pol : ClockPolarity::Low,
+            clk_pha : ClockPhase::FirstEdge,
+            prescaler : FreqPrescaler::Div256,
+        })
+    }
+
+    pub fn release(self) -> Spi {
+        self.spi.into_inner()
+    }
+}
+
+pub struct SpiDevice<'a> {
+    bus : &'a RefCell<Spi>,
+    cs : Gpio,
+    clk_pol : ClockPolarity,
+    clk_pha : ClockPhase,
+    prescaler : FreqPrescaler,
+}
+
+impl<'a> SpiDevice<'a> {
+    pub fn clock_polarity(mut self, cp : ClockPolarity) -> SpiDevice<'a> {
+        self.clk_pol = cp;
+        self
+    }
+
+    pub fn clock_phase(mut self, cp : ClockPhase) -> SpiDevice<'a> {
+        self.clk_pha = cp;
+        self
+    }
+
+    pub fn prescaler(mut self, psc : FreqPrescaler) -> SpiDevice<'a> {
+        self.prescaler = psc;
+        self
+    }
+
+    pub fn set_prescaler(&mut self, psc : FreqPrescaler) {
+        self.prescaler = psc;
+    }
+
+    // the device settings are applied, cs is asserted during f and released
+    // afterwards whatever f returned
+    pub fn transaction<F, R>(&mut self, f : F) -> SpiResult<R>
+        where F : FnOnce(&mut Spi) -> SpiResult<R>
+    {
+        let mut spi = self.bus.try_borrow_mut().map_err(|_| SpiError::BusBusy)?;
+        spi.set_clock(self.clk_pol, self.clk_pha, self.prescaler);
+
+        self.cs.set(State::Low).map_err(|_| SpiError::ChipSelect)?;
+        let res = f(&mut spi);
+        spi.wait_idle();
+        let released = self.cs.set(State::High).map_err(|_| SpiError::ChipSelect);
+
+        let r = res?;
+        released?;
+        Ok(r)
+    }
+
+    // same as transaction but cs stays released, e.g. for sd card wake up clocks
+    pub fn transaction_deselected<F, R>(&mut self, f : F) -> SpiResult<R>
+        where F : FnOnce(&mut Spi) -> SpiResult<R>
+    {
+        let mut spi = self.bus.try_borrow_mut().map_err(|_| SpiError::BusBusy)?;
+        spi.set_clock(self.clk_pol, self.clk_pha, self.prescaler);
+        let res = f(&mut spi);
+        spi.wait_idle();
+        res
+    }
+
+    pub fn release(self) -> Gpio {
+        self.cs
+    }
+}
+
+impl<'a> hal::blocking::spi::Transfer<u8> for SpiDevice<'a> {
+    type Error = SpiError;
+
+    fn transfer<'w>(&mut self, words : &'w mut [u8]) -> SpiResult<&'w [u8]> {
+        {
+            let buf = &mut *words;
+            self.transaction(|spi| spi.transfer(buf))?;
+        }
+        Ok(words)
+    }
+}
+
+impl<'a> hal::blocking::spi::Write<u8> for SpiDevice<'a> {
+    type Error = SpiError;
+
+    fn write(&mut self, words : &[u8]) -> SpiResult<()> {
+        self.transaction(|spi| spi.write(words))
+    }
+}
+
+impl<'a> nor_flash::Transport for SpiDevice<'a> {
+    type Error = SpiError;
+
+    fn read(&mut self, cmd : &[u8], buf : &mut [u8]) -> SpiResult<()> {
+        self.transaction(|spi| {
+            spi.write(cmd)?;
+            spi.transfer(buf)
+        })
+    }
+
+    fn write(&mut self, cmd : &[u8], data : &[u8]) -> SpiResult<()> {
+        self.transaction(|spi| {
+            spi.write(cmd)?;
+            spi.write(data)
+        })
+    }
+}
@@ -0,0 +1,150 @@
+use dma::{self, Channel, Direction, DmaConfig, Size};
+
+use spi::*;
+
+// the value has to outlive the call, one slot per peripheral
+static mut FILL_VALUE : [u16; 2] = [0; 2];
+
+impl Spi {
+    pub fn write_dma(&mut self, buf : &'static [u8], callback : Option<fn()>) -> SpiResult<()> {
+        self.check_format(DataFrameFormat::Bits8)?;
+        self.start_tx(buf.as_ptr() as u32, buf.len(), true, callback)
+    }
+
+    pub fn write16_dma(&mut self, buf : &'static [u16], callback : Option<fn()>) -> SpiResult<()> {
+        self.check_format(DataFrameFormat::Bits16)?;
+        self.start_tx(buf.as_ptr() as u32, buf.len(), true, callback)
+    }
+
+    // sends count times the same frame, e.g. to clear a display
+    pub fn fill_dma(&mut self, value : u16, count : usize, callback : Option<fn()>) -> SpiResult<()> {
+        let addr = unsafe {
+            FILL_VALUE[self.periph_index()] = value;
+            &FILL_VALUE[self.periph_index()] as *const u16 as u32
+        };
+        self.start_tx(addr, count, false, callback)
+    }
+
+    // 0xFF frames are clocked out while receiving
+    pub fn read_dma(&mut self, buf : &'static mut [u8], callback : Option<fn()>) -> SpiResult<()> {
+        self.check_format(DataFrameFormat::Bits8)?;
+        let len = buf.len();
+        self.start_rx(buf.as_mut_ptr() as u32, len, true, callback)?;
+        let addr = unsafe {
+            FILL_VALUE[self.periph_index()] = 0xFFFF;
+            &FILL_VALUE[self.periph_index()] as *const u16 as u32
+        };
+        self.start_tx(addr, len, false, None).map_err(|e| self.abort_rx(e))
+    }
+
+    // both buffers must have the same length
+    pub fn transfer_dma(&mut self, tx : &'static [u8], rx : &'static mut [u8], callback : Option<fn()>) -> SpiResult<()> {
+        self.check_format(DataFrameFormat::Bits8)?;
+        if tx.len() != rx.len() {
+            return Err(SpiError::ConfigError);
+        }
+        self.start_rx(rx.as_mut_ptr() as u32, rx.len(), true, callback)?;
+        self.start_tx(tx.as_ptr() as u32, tx.len(), true, None).map_err(|e| self.abort_rx(e))
+    }
+
+    pub fn dma_busy(&self) -> bool {
+        let (rx, tx) = self.dma_channels();
+        let rx_busy = regs(self.periph).cr2.read().rxdmaen().bit() && dma::is_enabled(rx);
+        let tx_busy = regs(self.periph).cr2.read().txdmaen().bit() && dma::is_enabled(tx);
+        rx_busy || tx_busy
+    }
+
+    // waits for the end of the dma transfer and gives the spi back for polled transfers
+    pub fn wait_dma(&mut self) -> SpiResult<()> {
+        while self.dma_busy() {}
+        self.wait_idle();
+
+        let spi = regs(self.periph);
+        let rx_used = spi.cr2.read().rxdmaen().bit();
+        spi.cr2.modify(|_, w| {
+            w.rxdmaen().bit(false)
+                .txdmaen().bit(false)
+        });
+        // tx only transfers leave received frames behind
+        if !rx_used {
+            spi.dr.read();
+            spi.sr.read();
+        }
+        Ok(())
+    }
+
+    // undoes start_rx when the tx side couldn't be started
+    fn abort_rx(&mut self, e : SpiError) -> SpiError {
+        let (rx, _) = self.dma_channels();
+        dma::stop(rx);
+        regs(self.periph).cr2.modify(|_, w| w.rxdmaen().bit(false));
+        e
+    }
+
+    fn periph_index(&self) -> usize {
+        match self.periph {
+            Periph::Spi1 => 0,
+            Periph::Spi2 => 1,
+        }
+    }
+
+    // rx, tx
+    fn dma_channels(&self) -> (Channel, Channel) {
+        match self.periph {
+            Periph::Spi1 => (Channel::Ch2, Channel::Ch3),
+            Periph::Spi2 => (Channel::Ch4, Channel::Ch5),
+        }
+    }
+
+    fn dma_size(&self) -> Size {
+        match self.data_frame_format {
+            DataFrameFormat::Bits8 => Size::Bits8,
+            DataFrameFormat::Bits16 => Size::Bits16,
+        }
+    }
+
+    fn start_tx(&mut self, addr : u32, len : usize, inc : bool, callback : Option<fn()>) -> SpiResult<()> {
+        if len > 0xFFFF {
+            return Err(SpiError::ConfigError);
+        }
+        let (_, tx) = self.dma_channels();
+        let spi = regs(self.periph);
+        DmaConfig::new()
+            .channel(tx)
+            .direction(Direction::MemToPeriph)
+            .size(self.dma_size())
+            .periph_addr(&spi.dr as *const _ as u32)
+            .mem_addr(addr)
+            .len(len as u16)
+            .mem_increment(inc)
+            .callback(callback)
+            .start()
+            .map_err(|_| SpiError::DmaError)?;
+        // enabling the request starts the transfer
+        spi.cr2.modify(|_, w| w.txdmaen().bit(true));
+        Ok(())
+    }
+
+    fn start_rx(&mut self, addr : u32, len : usize, inc : bool, callback : Option<fn()>) -> SpiResult<()> {
+        if len > 0xFFFF {
+            return Err(SpiError::ConfigError);
+        }
+        let (rx, _) = self.dma_channels();
+        let spi = regs(self.periph);
+        // drop a frame left from a previous polled transfer
+        spi.dr.read();
+        DmaConfig::new()
+            .channel(rx)
+            .direction(Direction::PeriphToMem)
+            .size(self.dma_size())
+            .periph_addr(&spi.dr as *const _ as u32)
+            .mem_addr(addr)
+            .len(len as u16)
+            .mem_increment(inc)
+            .callback(callback)
+            .start()
+            .map_err(|_| SpiError::DmaError)?;
+        spi.cr2.modify(|_, w| w.rxdmaen().bit(true));
+        Ok(())
+    }
+}
@@ -1,60 +1,86 @@
-use stm32f103xx::{SPI1, SPI2, RCC, GPIOA, GPIOB}
+use stm32f103xx::{SPI1, SPI2, RCC, AFIO, Interrupt};
+use stm32f103xx::spi1;
 
+use cortex_m::peripheral::NVIC;
+
+use hal;
+use nb;
+
+use gpio::*;
+
+pub mod bus;
+pub mod dma;
+pub mod slave;
+
+#[derive(Debug)]
 pub enum SpiError {
     ConfigError,
+    FrameFormat,
+    Overrun,
+    ModeFault,
+    BusBusy,
+    ChipSelect,
+    DmaError,
+    Crc,
 }
 
 type SpiResult<T> = Result<T, SpiError>;
 
-#[derive(Copy, Clone)]
+#[derive(Eq, PartialEq, Copy, Clone)]
 pub enum Periph {
     Spi1,
     Spi2,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Eq, PartialEq, Copy, Clone)]
 pub enum PinSet {
     First,
     Second,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Eq, PartialEq, Copy, Clone)]
 pub enum DataFrameFormat {
     Bits8,
     Bits16,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Eq, PartialEq, Copy, Clone)]
 pub enum DirFrameFormat {
     MsbFirst,
     LsbFirst,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Eq, PartialEq, Copy, Clone)]
 pub enum FreqPrescaler {
-    Div2,
-    Div4,
-    Div8,
-    Div16,
-    Div32,
-    Div64,
-    Div128,
-    Div256,
+    Div2    = 0b000,
+    Div4    = 0b001,
+    Div8    = 0b010,
+    Div16   = 0b011,
+    Div32   = 0b100,
+    Div64   = 0b101,
+    Div128  = 0b110,
+    Div256  = 0b111,
+}
+
+impl FreqPrescaler {
+    pub fn as_code(&self) -> u8 {
+        (*self as u8)
+    }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Eq, PartialEq, Copy, Clone)]
 pub enum ClockPolarity {
     Low,
     High,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Eq, PartialEq, Copy, Clone)]
 pub enum ClockPhase {
     FirstEdge,
     SecondEdge,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Eq, PartialEq, Copy, Clone)]
 pub enum DataMode {
     FullDuplex,
     FullDuplexRecv,
@@ -62,9 +88,25 @@ pub enum DataMode {
     HalfDuplexSend,
 }
 
+impl DataMode {
+    pub fn is_half_duplex(&self) -> bool {
+        *self == DataMode::HalfDuplexRecv || *self == DataMode::HalfDuplexSend
+    }
+
+    pub fn is_receive_only(&self) -> bool {
+        *self == DataMode::FullDuplexRecv || *self == DataMode::HalfDuplexRecv
+    }
+}
+
+#[derive(Eq, PartialEq, Copy, Clone)]
+pub enum Direction {
+    Send,
+    Receive,
+}
+
 pub struct SpiConfig {
-    periph : SpiPeriph,
-    pin_set : u8,
+    periph : Periph,
+    pin_set : PinSet,
     data_frame_format : DataFrameFormat,
     data_dir : DirFrameFormat,
     prescaler : FreqPrescaler,
@@ -72,11 +114,12 @@ pub struct SpiConfig {
     clk_pol : ClockPolarity,
     clk_pha : ClockPhase,
     data_mode : DataMode,
+    crc : Option<u16>,
 }
 
 pub struct Spi {
-    periph : SpiPeriph,
-    pin_set : u8,
+    periph : Periph,
+    pin_set : PinSet,
     data_frame_format : DataFrameFormat,
     data_dir : DirFrameFormat,
     prescaler : FreqPrescaler,
@@ -84,13 +127,14 @@ pub struct Spi {
     clk_pol : ClockPolarity,
     clk_pha : ClockPhase,
     data_mode : DataMode,
+    crc : Option<u16>,
 }
 
 impl SpiConfig {
     pub fn new() -> SpiConfig {
         SpiConfig {
-            periph : SpiPeriph::Spi1,
-            pin_set : 1,
+            periph : Periph::Spi1,
+            pin_set : PinSet::First,
             data_frame_format : DataFrameFormat::Bits8,
             data_dir : DirFrameFormat::MsbFirst,
             prescaler : FreqPrescaler::Div4,
@@ -98,69 +142,558 @@ impl SpiConfig {
             clk_pol : ClockPolarity::Low,
             clk_pha : ClockPhase::FirstEdge,
             data_mode : DataMode::FullDuplex,
+            crc : None,
         }
     }
 
-    pub fn instance(self, p : SpiPeriph) -> self {
+    pub fn instance(mut self, p : Periph) -> SpiConfig {
         self.periph = p;
         self
     }
 
-    pub fn pin_set(self, ps : u8) -> self {
+    pub fn pin_set(mut self, ps : PinSet) -> SpiConfig {
         self.pin_set = ps;
         self
     }
 
-    pub fn data_frame_format(self, dff : DataFrameFormat) -> self {
+    pub fn data_frame_format(mut self, dff : DataFrameFormat) -> SpiConfig {
         self.data_frame_format = dff;
         self
     }
 
-    pub fn dir_frame_format(self, dff : DirFrameFormat) -> self {
-        self.dir_frame_format = dff;
+    pub fn dir_frame_format(mut self, dff : DirFrameFormat) -> SpiConfig {
+        self.data_dir = dff;
         self
     }
 
-    pub fn prescaler(self, psc : FreqPrescaler) -> self {
+    pub fn prescaler(mut self, psc : FreqPrescaler) -> SpiConfig {
         self.prescaler = psc;
         self
     }
 
-    pub fn clock_polarity(self, cp : ClockPolarity) -> self {
+    pub fn clock_polarity(mut self, cp : ClockPolarity) -> SpiConfig {
         self.clk_pol = cp;
         self
     }
 
-    pub fn clock_phase(self, cp : ClockPhase) -> self {
+    pub fn clock_phase(mut self, cp : ClockPhase) -> SpiConfig {
         self.clk_pha = cp;
         self
     }
 
-    pub fn master(self, en : bool) -> self {
+    pub fn master(mut self, en : bool) -> SpiConfig {
         self.master = en;
         self
     }
 
-    pub fn data_mode(self, dm : DataMode) -> self {
+    pub fn data_mode(mut self, dm : DataMode) -> SpiConfig {
         self.data_mode = dm;
         self
     }
 
-    pub fn configure(&mut self) -> SpiResult<Spi> {
+    // hardware crc on every transfer, with the given polynomial. master only
+    pub fn crc(mut self, polynomial : u16) -> SpiConfig {
+        self.crc = Some(polynomial);
+        self
+    }
+
+    pub fn configure(&self) -> SpiResult<Spi> {
+        // spi2 has no remap
+        if self.periph == Periph::Spi2 && self.pin_set == PinSet::Second {
+            return Err(SpiError::ConfigError);
+        }
+        // the slave buffers are only handled for the two wires modes
+        if !self.master && self.data_mode.is_half_duplex() {
+            return Err(SpiError::ConfigError);
+        }
+        // the slave interrupt has no notion of blocks, so no crc either
+        if !self.master && self.crc.is_some() {
+            return Err(SpiError::ConfigError);
+        }
+
+        unsafe {
+            match self.periph {
+                Periph::Spi1 => {
+                    (*RCC.get()).apb2enr.modify(|_, w| w.spi1en().bit(true));
+                    (*RCC.get()).apb2enr.modify(|_, w| w.afioen().bit(true));
+                    match self.pin_set {
+                        PinSet::First => {
+                            (*AFIO.get()).mapr.modify(|_, w| w.spi1_remap().bit(false));
+                        },
+                        PinSet::Second => {
+                            // PB3 and PB4 are jtag pins at reset, keep only swd
+                            (*AFIO.get()).mapr.modify(|_, w| {
+                                w.swj_cfg().bits(0b010)
+                                    .spi1_remap().bit(true)
+                            });
+                        },
+                    }
+                },
+                Periph::Spi2 => {
+                    (*RCC.get()).apb1enr.modify(|_, w| w.spi2en().bit(true));
+                },
+            }
+        }
+
+        let (port, sck, miso, mosi) = pins(self.periph, self.pin_set);
+        if self.master {
+            configure_pin(port, sck, Conf::AltFnPushPullOut, Mode::Output50MHz)?;
+            match self.data_mode {
+                DataMode::FullDuplex => {
+                    configure_pin(port, miso, Conf::FloatingIn, Mode::Input)?;
+                    configure_pin(port, mosi, Conf::AltFnPushPullOut, Mode::Output50MHz)?;
+                },
+                DataMode::FullDuplexRecv => {
+                    configure_pin(port, miso, Conf::FloatingIn, Mode::Input)?;
+                },
+                // the single data line of a master is mosi
+                DataMode::HalfDuplexRecv | DataMode::HalfDuplexSend => {
+                    configure_pin(port, mosi, Conf::AltFnPushPullOut, Mode::Output50MHz)?;
+                },
+            }
+        } else {
+            let (nss_port, nss) = nss_pin(self.periph, self.pin_set);
+            configure_pin(port, sck, Conf::FloatingIn, Mode::Input)?;
+            if self.data_mode == DataMode::FullDuplex {
+                configure_pin(port, miso, Conf::AltFnPushPullOut, Mode::Output50MHz)?;
+            }
+            configure_pin(port, mosi, Conf::FloatingIn, Mode::Input)?;
+            configure_pin(nss_port, nss, Conf::FloatingIn, Mode::Input)?;
+        }
+
+        let spi = regs(self.periph);
+        spi.cr1.write(|w| unsafe {
+            w.br().bits(self.prescaler.as_code())
+                .cpol().bit(self.clk_pol == ClockPolarity::High)
+                .cpha().bit(self.clk_pha == ClockPhase::SecondEdge)
+                .dff().bit(self.data_frame_format == DataFrameFormat::Bits16)
+                .lsbfirst().bit(self.data_dir == DirFrameFormat::LsbFirst)
+                // master : chip selects are handled in software, nss is kept high internally
+                // slave : selected by the hardware nss pin
+                .ssm().bit(self.master)
+                .ssi().bit(self.master)
+                .mstr().bit(self.master)
+                .bidimode().bit(self.data_mode.is_half_duplex())
+                .bidioe().bit(self.data_mode == DataMode::HalfDuplexSend)
+                .rxonly().bit(self.data_mode == DataMode::FullDuplexRecv)
+        });
+
+        if let Some(poly) = self.crc {
+            // crcen can only be changed with the spi disabled
+            spi.crcpr.write(|w| unsafe { w.bits(poly as u32) });
+            spi.cr1.modify(|_, w| w.crcen().bit(true));
+        }
+
+        // receive only slaves are read with receive(), the interrupt would
+        // steal the frames
+        if !self.master && !self.data_mode.is_receive_only() {
+            slave::reset_buffers(self.periph);
+            // the first frame sent to the master
+            spi.dr.write(|w| unsafe { w.bits(slave::IDLE_FRAME as u32) });
+            spi.cr2.modify(|_, w| w.rxneie().bit(true));
+            unsafe {
+                (*NVIC.get()).enable(match self.periph {
+                    Periph::Spi1 => Interrupt::SPI1,
+                    Periph::Spi2 => Interrupt::SPI2,
+                });
+            }
+        }
+
+        // a receiving master clocks as soon as it is enabled, wait for receive()
+        if !(self.master && self.data_mode.is_receive_only()) {
+            spi.cr1.modify(|_, w| w.spe().bit(true));
+        }
 
         Ok(
             Spi {
                 periph : self.periph,
                 pin_set : self.pin_set,
                 data_frame_format : self.data_frame_format,
-                data_dir : DirFrameFormat,
-                prescaler : FreqPrescaler,
-                master : bool,
-                clk_pol : ClockPolarity,
-                clk_pha : ClockPhase,
-                data_mode : DataMode,
+                data_dir : self.data_dir,
+                prescaler : self.prescaler,
+                master : self.master,
+                clk_pol : self.clk_pol,
+                clk_pha : self.clk_pha,
+                data_mode : self.data_mode,
+                crc : self.crc,
             }
         )
     }
 }
 
+impl Spi {
+    pub fn periph(&self) -> Periph {
+        self.periph
+    }
+
+    pub fn pin_set(&self) -> PinSet {
+        self.pin_set
+    }
+
+    pub fn data_frame_format(&self) -> DataFrameFormat {
+        self.data_frame_format
+    }
+
+    // the bus is briefly disabled, only call between transactions
+    pub fn set_clock(&mut self, pol : ClockPolarity, pha : ClockPhase, psc : FreqPrescaler) {
+        if self.clk_pol == pol && self.clk_pha == pha && self.prescaler == psc {
+            return;
+        }
+        self.clk_pol = pol;
+        self.clk_pha = pha;
+        self.prescaler = psc;
+
+        let spi = regs(self.periph);
+        let enabled = spi.cr1.read().spe().bit();
+        self.wait_idle();
+        spi.cr1.modify(|_, w| w.spe().bit(false));
+        spi.cr1.modify(|_, w| unsafe {
+            w.br().bits(psc.as_code())
+                .cpol().bit(pol == ClockPolarity::High)
+                .cpha().bit(pha == ClockPhase::SecondEdge)
+        });
+        spi.cr1.modify(|_, w| w.spe().bit(enabled));
+    }
+
+    pub fn data_mode(&self) -> DataMode {
+        self.data_mode
+    }
+
+    // half-duplex only, turns the single data line around
+    pub fn set_direction(&mut self, dir : Direction) -> SpiResult<()> {
+        if !self.data_mode.is_half_duplex() {
+            return Err(SpiError::ConfigError);
+        }
+        let spi = regs(self.periph);
+        if spi.cr1.read().spe().bit() {
+            self.wait_idle();
+        }
+        match dir {
+            Direction::Send => {
+                spi.cr1.modify(|_, w| w.bidioe().bit(true));
+                spi.cr1.modify(|_, w| w.spe().bit(true));
+                self.data_mode = DataMode::HalfDuplexSend;
+            },
+            Direction::Receive => {
+                // stop first, the master would start clocking right away
+                spi.cr1.modify(|_, w| w.spe().bit(false));
+                spi.cr1.modify(|_, w| w.bidioe().bit(false));
+                if !self.master {
+                    spi.cr1.modify(|_, w| w.spe().bit(true));
+                }
+                self.data_mode = DataMode::HalfDuplexRecv;
+            },
+        }
+        Ok(())
+    }
+
+    // receive only modes, the master clocks exactly buf.len() frames
+    pub fn receive(&mut self, buf : &mut [u8]) -> SpiResult<()> {
+        self.check_format(DataFrameFormat::Bits8)?;
+        if !self.data_mode.is_receive_only() {
+            return Err(SpiError::ConfigError);
+        }
+        let len = buf.len();
+        if len == 0 {
+            return Ok(());
+        }
+        let spi = regs(self.periph);
+        if !self.master {
+            for b in buf.iter_mut() {
+                *b = self.wait_frame()? as u8;
+            }
+            return Ok(());
+        }
+
+        // the clock only stops when the spi is disabled, this has to happen
+        // during the last frame so no extra frame is clocked
+        spi.cr1.modify(|_, w| w.spe().bit(true));
+        if len == 1 {
+            self.wait_one_clock();
+            spi.cr1.modify(|_, w| w.spe().bit(false));
+        }
+        for i in 0..len {
+            buf[i] = self.wait_frame()? as u8;
+            if len > 1 && i == len - 2 {
+                self.wait_one_clock();
+                spi.cr1.modify(|_, w| w.spe().bit(false));
+            }
+        }
+        Ok(())
+    }
+
+    fn wait_one_clock(&self) {
+        // spi clock is at most core / 2, one clock period is 2 << br core cycles
+        let cycles = 2u32 << self.prescaler.as_code();
+        for _ in 0..cycles {
+            ::cortex_m::asm::nop();
+        }
+    }
+
+    // wait for the last frame to be completely shifted out
+    pub fn wait_idle(&self) {
+        let spi = regs(self.periph);
+        while !spi.sr.read().txe().bit() {}
+        while spi.sr.read().bsy().bit() {}
+    }
+
+    // with crc enabled, each call is one crc protected block
+    pub fn transfer(&mut self, buf : &mut [u8]) -> SpiResult<()> {
+        self.check_format(DataFrameFormat::Bits8)?;
+        self.check_full_duplex()?;
+        self.reset_crc();
+        let len = buf.len();
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = self.exchange(*b as u16, i + 1 == len)? as u8;
+        }
+        self.check_crc()
+    }
+
+    pub fn write(&mut self, buf : &[u8]) -> SpiResult<()> {
+        self.check_format(DataFrameFormat::Bits8)?;
+        self.check_can_send()?;
+        self.reset_crc();
+        let len = buf.len();
+        for (i, &b) in buf.iter().enumerate() {
+            self.exchange(b as u16, i + 1 == len)?;
+        }
+        self.check_crc()
+    }
+
+    pub fn transfer16(&mut self, buf : &mut [u16]) -> SpiResult<()> {
+        self.check_format(DataFrameFormat::Bits16)?;
+        self.check_full_duplex()?;
+        self.reset_crc();
+        let len = buf.len();
+        for (i, w) in buf.iter_mut().enumerate() {
+            *w = self.exchange(*w, i + 1 == len)?;
+        }
+        self.check_crc()
+    }
+
+    pub fn write16(&mut self, buf : &[u16]) -> SpiResult<()> {
+        self.check_format(DataFrameFormat::Bits16)?;
+        self.check_can_send()?;
+        self.reset_crc();
+        let len = buf.len();
+        for (i, &w) in buf.iter().enumerate() {
+            self.exchange(w, i + 1 == len)?;
+        }
+        self.check_crc()
+    }
+
+    pub fn crc_enabled(&self) -> bool {
+        self.crc.is_some()
+    }
+
+    // the crc is sent after the current frame
+    pub fn crc_next(&mut self) {
+        regs(self.periph).cr1.modify(|_, w| w.crcnext().bit(true));
+    }
+
+    // reports and clears a crc mismatch on the last received block
+    pub fn check_crc(&mut self) -> SpiResult<()> {
+        let spi = regs(self.periph);
+        if spi.sr.read().crcerr().bit() {
+            spi.sr.modify(|_, w| w.crcerr().bit(false));
+            return Err(SpiError::Crc);
+        }
+        Ok(())
+    }
+
+    pub fn reset_crc(&mut self) {
+        if self.crc.is_none() {
+            return;
+        }
+        let spi = regs(self.periph);
+        let enabled = spi.cr1.read().spe().bit();
+        self.wait_idle();
+        spi.cr1.modify(|_, w| w.spe().bit(false));
+        spi.cr1.modify(|_, w| w.crcen().bit(false));
+        spi.cr1.modify(|_, w| w.crcen().bit(true));
+        spi.cr1.modify(|_, w| w.spe().bit(enabled));
+    }
+
+    fn check_full_duplex(&self) -> SpiResult<()> {
+        if self.data_mode != DataMode::FullDuplex {
+            return Err(SpiError::ConfigError);
+        }
+        Ok(())
+    }
+
+    fn check_can_send(&self) -> SpiResult<()> {
+        if self.data_mode.is_receive_only() {
+            return Err(SpiError::ConfigError);
+        }
+        Ok(())
+    }
+
+    fn check_format(&self, dff : DataFrameFormat) -> SpiResult<()> {
+        if self.data_frame_format != dff {
+            return Err(SpiError::FrameFormat);
+        }
+        Ok(())
+    }
+
+    // send one frame and wait for the frame received at the same time,
+    // the crc follows the last frame of a block when enabled
+    fn exchange(&mut self, word : u16, last : bool) -> SpiResult<u16> {
+        let spi = regs(self.periph);
+        while !spi.sr.read().txe().bit() {}
+        spi.dr.write(|w| unsafe { w.bits(word as u32) });
+        if last && self.crc.is_some() {
+            self.crc_next();
+        }
+        // nothing comes back on a half-duplex line while sending
+        if self.data_mode == DataMode::HalfDuplexSend {
+            if last {
+                self.wait_idle();
+            }
+            return Ok(0);
+        }
+        let received = self.wait_frame()?;
+        if last && self.crc.is_some() {
+            // received crc, only checked by the hardware
+            self.wait_frame()?;
+        }
+        Ok(received)
+    }
+
+    fn wait_frame(&mut self) -> SpiResult<u16> {
+        loop {
+            match self.read_frame() {
+                Ok(w) => return Ok(w),
+                Err(nb::Error::Other(e)) => return Err(e),
+                Err(nb::Error::WouldBlock) => {},
+            }
+        }
+    }
+
+    fn read_frame(&mut self) -> nb::Result<u16, SpiError> {
+        let spi = regs(self.periph);
+        let sr = spi.sr.read();
+        if sr.ovr().bit() {
+            // cleared by reading dr then sr
+            spi.dr.read();
+            spi.sr.read();
+            Err(nb::Error::Other(SpiError::Overrun))
+        } else if sr.modf().bit() {
+            // cleared by reading sr then writing cr1, mstr has been reset by the fault
+            spi.cr1.modify(|_, w| w.mstr().bit(true));
+            Err(nb::Error::Other(SpiError::ModeFault))
+        } else if sr.rxne().bit() {
+            Ok(spi.dr.read().bits() as u16)
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+
+    fn send_frame(&mut self, word : u16) -> nb::Result<(), SpiError> {
+        let spi = regs(self.periph);
+        if spi.sr.read().txe().bit() {
+            spi.dr.write(|w| unsafe { w.bits(word as u32) });
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+}
+
+impl hal::spi::FullDuplex<u8> for Spi {
+    type Error = SpiError;
+
+    fn read(&mut self) -> nb::Result<u8, SpiError> {
+        self.check_format(DataFrameFormat::Bits8)?;
+        self.read_frame().map(|w| w as u8)
+    }
+
+    fn send(&mut self, word : u8) -> nb::Result<(), SpiError> {
+        self.check_format(DataFrameFormat::Bits8)?;
+        self.send_frame(word as u16)
+    }
+}
+
+impl hal::spi::FullDuplex<u16> for Spi {
+    type Error = SpiError;
+
+    fn read(&mut self) -> nb::Result<u16, SpiError> {
+        self.check_format(DataFrameFormat::Bits16)?;
+        self.read_frame()
+    }
+
+    fn send(&mut self, word : u16) -> nb::Result<(), SpiError> {
+        self.check_format(DataFrameFormat::Bits16)?;
+        self.send_frame(word)
+    }
+}
+
+impl hal::blocking::spi::Transfer<u8> for Spi {
+    type Error = SpiError;
+
+    fn transfer<'w>(&mut self, words : &'w mut [u8]) -> SpiResult<&'w [u8]> {
+        Spi::transfer(self, words)?;
+        Ok(words)
+    }
+}
+
+impl hal::blocking::spi::Write<u8> for Spi {
+    type Error = SpiError;
+
+    fn write(&mut self, words : &[u8]) -> SpiResult<()> {
+        Spi::write(self, words)
+    }
+}
+
+impl hal::blocking::spi::Transfer<u16> for Spi {
+    type Error = SpiError;
+
+    fn transfer<'w>(&mut self, words : &'w mut [u16]) -> SpiResult<&'w [u16]> {
+        Spi::transfer16(self, words)?;
+        Ok(words)
+    }
+}
+
+impl hal::blocking::spi::Write<u16> for Spi {
+    type Error = SpiError;
+
+    fn write(&mut self, words : &[u16]) -> SpiResult<()> {
+        Spi::write16(self, words)
+    }
+}
+
+// spi1 and spi2 share the same register layout
+fn regs(p : Periph) -> &'static spi1::RegisterBlock {
+    unsafe {
+        match p {
+            Periph::Spi1 => &*SPI1.get(),
+            Periph::Spi2 => &*(SPI2.get() as *const spi1::RegisterBlock),
+        }
+    }
+}
+
+fn nss_pin(p : Periph, ps : PinSet) -> (Port, Pin) {
+    match (p, ps) {
+        (Periph::Spi1, PinSet::First) => (Port::A, Pin(4)),
+        (Periph::Spi1, PinSet::Second) => (Port::A, Pin(15)),
+        (Periph::Spi2, _) => (Port::B, Pin(12)),
+    }
+}
+
+// port, sck, miso, mosi
+fn pins(p : Periph, ps : PinSet) -> (Port, Pin, Pin, Pin) {
+    match (p, ps) {
+        (Periph::Spi1, PinSet::First) => (Port::A, Pin(5), Pin(6), Pin(7)),
+        (Periph::Spi1, PinSet::Second) => (Port::B, Pin(3), Pin(4), Pin(5)),
+        (Periph::Spi2, _) => (Port::B, Pin(13), Pin(14), Pin(15)),
+    }
+}
+
+fn configure_pin(port : Port, pin : Pin, conf : Conf, mode : Mode) -> SpiResult<Gpio> {
+    GpioConfig::new()
+        .port(port)
+        .pin(pin)
+        .conf(conf)
+        .mode(mode)
+        .configure()
+        .map_err(|_| SpiError::ConfigError)
+}
@@ -0,0 +1,142 @@
+use cortex_m::interrupt;
+
+use spi::*;
+
+pub const SLAVE_BUFFER_LEN : usize = 64;
+
+// sent to the master when nothing is queued
+pub const IDLE_FRAME : u16 = 0xFF;
+
+struct Ring {
+    data : [u16; SLAVE_BUFFER_LEN],
+    head : usize,
+    count : usize,
+    dropped : u32,
+}
+
+impl Ring {
+    fn clear(&mut self) {
+        self.head = 0;
+        self.count = 0;
+        self.dropped = 0;
+    }
+
+    fn push(&mut self, w : u16) -> bool {
+        if self.count == SLAVE_BUFFER_LEN {
+            self.dropped = self.dropped.wrapping_add(1);
+            return false;
+        }
+        self.data[(self.head + self.count) % SLAVE_BUFFER_LEN] = w;
+        self.count += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u16> {
+        if self.count == 0 {
+            return None;
+        }
+        let w = self.data[self.head];
+        self.head = (self.head + 1) % SLAVE_BUFFER_LEN;
+        self.count -= 1;
+        Some(w)
+    }
+}
+
+const EMPTY_RING : Ring = Ring {
+    data : [0; SLAVE_BUFFER_LEN],
+    head : 0,
+    count : 0,
+    dropped : 0,
+};
+
+// one rx and one tx buffer per peripheral, filled and emptied by the spi interrupt
+static mut RX : [Ring; 2] = [EMPTY_RING, EMPTY_RING];
+static mut TX : [Ring; 2] = [EMPTY_RING, EMPTY_RING];
+
+fn index(p : Periph) -> usize {
+    match p {
+        Periph::Spi1 => 0,
+        Periph::Spi2 => 1,
+    }
+}
+
+pub fn reset_buffers(p : Periph) {
+    interrupt::free(|_| unsafe {
+        RX[index(p)].clear();
+        TX[index(p)].clear();
+    });
+}
+
+impl Spi {
+    // frames answered to the master on the next transfers, returns how many were queued
+    pub fn slave_queue(&mut self, data : &[u8]) -> usize {
+        let idx = index(self.periph);
+        interrupt::free(|_| unsafe {
+            let mut n = 0;
+            for &b in data {
+                if TX[idx].count == SLAVE_BUFFER_LEN {
+                    break;
+                }
+                TX[idx].push(b as u16);
+                n += 1;
+            }
+            n
+        })
+    }
+
+    // frames received from the master, returns how many were copied
+    pub fn slave_read(&mut self, buf : &mut [u8]) -> usize {
+        let idx = index(self.periph);
+        interrupt::free(|_| unsafe {
+            let mut n = 0;
+            for b in buf.iter_mut() {
+                match RX[idx].pop() {
+                    Some(w) => *b = w as u8,
+                    None => break,
+                }
+                n += 1;
+            }
+            n
+        })
+    }
+
+    pub fn slave_available(&self) -> usize {
+        let idx = index(self.periph);
+        interrupt::free(|_| unsafe { RX[idx].count })
+    }
+
+    // frames lost because the rx buffer was full
+    pub fn slave_dropped(&self) -> u32 {
+        let idx = index(self.periph);
+        interrupt::free(|_| unsafe { RX[idx].dropped })
+    }
+}
+
+fn handler(p : Periph) {
+    let spi = regs(p);
+    let idx = index(p);
+    let sr = spi.sr.read();
+    unsafe {
+        if sr.ovr().bit() {
+            spi.dr.read();
+            spi.sr.read();
+            RX[idx].dropped = RX[idx].dropped.wrapping_add(1);
+            return;
+        }
+        if sr.rxne().bit() {
+            let w = spi.dr.read().bits() as u16;
+            RX[idx].push(w);
+            // load the frame for the next transfer
+            let next = TX[idx].pop().unwrap_or(IDLE_FRAME);
+            spi.dr.write(|w| w.bits(next as u32));
+        }
+    }
+}
+
+pub fn spi1_handler() {
+    handler(Periph::Spi1);
+}
+
+pub fn spi2_handler() {
+    handler(Periph::Spi2);
+}
@@ -0,0 +1,470 @@
+use cortex_m::interrupt;
+use cortex_m::peripheral::NVIC;
+use stm32f103xx::{TIM1, Interrupt};
+use gpio::*;
+use timing::timer::{self, TimerId, Channel, OutputMode, SlaveMode};
+
+#[derive(Debug)]
+pub enum CompareError {
+    InvalidFrequency,
+    InvalidPulse,
+    ConfigError,
+    // a one-pulse is already running
+    Busy,
+}
+
+type CompareResult<T> = Result<T, CompareError>;
+
+// what the channel output does at the compare match
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum CompareAction {
+    // output unchanged, only the callback
+    None,
+    Active,
+    Inactive,
+    Toggle,
+}
+
+impl CompareAction {
+    fn mode(&self) -> OutputMode {
+        match *self {
+            CompareAction::None => OutputMode::Frozen,
+            CompareAction::Active => OutputMode::ActiveOnMatch,
+            CompareAction::Inactive => OutputMode::InactiveOnMatch,
+            CompareAction::Toggle => OutputMode::Toggle,
+        }
+    }
+}
+
+const CR1_OPM : u32 = 1 << 3;
+const DIER_CC1IE : u32 = 1 << 1;
+const SR_CC1IF : u32 = 1 << 1;
+const BDTR_MOE : u32 = 1 << 15;
+
+// TS codes of the filtered timer inputs
+const TS_TI1FP1 : u32 = 0b101;
+const TS_TI2FP2 : u32 = 0b110;
+
+// scheduled compare callbacks per timer and channel, one-shot
+static mut CALLBACKS : [[Option<fn()>; 4]; 4] = [[None; 4]; 4];
+static mut ARMED : [[bool; 4]; 4] = [[false; 4]; 4];
+
+fn index(id : TimerId) -> usize {
+    match id {
+        TimerId::Tim1 => 0,
+        TimerId::Tim2 => 1,
+        TimerId::Tim3 => 2,
+        TimerId::Tim4 => 3,
+    }
+}
+
+// psc for the requested tick frequency, returns the actual frequency
+fn set_tick_frequency(id : TimerId, hz : u32) -> CompareResult<u32> {
+    let clk = timer::clock(id);
+    if hz == 0 || hz > clk || clk / hz > 0x1_0000 {
+        return Err(CompareError::InvalidFrequency);
+    }
+    let psc = clk / hz - 1;
+    unsafe {
+        timer::regs(id).psc.write(|w| w.bits(psc));
+    }
+    Ok(clk / (psc + 1))
+}
+
+fn configure_output(id : TimerId, ch : Channel) -> CompareResult<()> {
+    let (port, pin) = timer::channel_pin(id, ch);
+    GpioConfig::new()
+        .port(port)
+        .pin(pin)
+        .conf(Conf::AltFnPushPullOut)
+        .mode(Mode::Output50MHz)
+        .configure()
+        .map_err(|_| CompareError::ConfigError)?;
+    if id == TimerId::Tim1 {
+        unsafe {
+            (*TIM1.get()).bdtr.modify(|r, w| w.bits(r.bits() | BDTR_MOE));
+        }
+    }
+    Ok(())
+}
+
+fn enable_interrupt(id : TimerId) {
+    unsafe {
+        (*NVIC.get()).enable(match id {
+            TimerId::Tim1 => Interrupt::TIM1_CC,
+            TimerId::Tim2 => Interrupt::TIM2,
+            TimerId::Tim3 => Interrupt::TIM3,
+            TimerId::Tim4 => Interrupt::TIM4,
+        });
+    }
+}
+
+pub struct OnePulseConfig {
+    timer : TimerId,
+    channel : Channel,
+    tick_frequency : u32,
+    delay : u16,
+    width : u16,
+    active_low : bool,
+    trigger : Option<Channel>,
+}
+
+impl OnePulseConfig {
+    pub fn new(timer : TimerId, channel : Channel) -> OnePulseConfig {
+        OnePulseConfig {
+            timer,
+            channel,
+            tick_frequency : 1_000_000,
+            delay : 1,
+            width : 1,
+            active_low : false,
+            trigger : None,
+        }
+    }
+
+    pub fn tick_frequency(mut self, hz : u32) -> OnePulseConfig {
+        self.tick_frequency = hz;
+        self
+    }
+
+    // ticks from the trigger to the pulse, at least 1
+    pub fn delay_ticks(mut self, delay : u16) -> OnePulseConfig {
+        self.delay = delay;
+        self
+    }
+
+    pub fn width_ticks(mut self, width : u16) -> OnePulseConfig {
+        self.width = width;
+        self
+    }
+
+    pub fn active_low(mut self, active_low : bool) -> OnePulseConfig {
+        self.active_low = active_low;
+        self
+    }
+
+    // rising edge on ch1 or ch2 input starting the pulse, in addition to fire.
+    // it can't be the pulse channel nor its pair
+    pub fn external_trigger(mut self, input : Channel) -> OnePulseConfig {
+        self.trigger = Some(input);
+        self
+    }
+
+    // the counter runs from 0 to delay + width - 1 once per trigger, the
+    // output is in pwm mode 2 so it is active from delay to the end
+    pub fn configure(&self) -> CompareResult<OnePulse> {
+        let arr = pulse_reload(self.delay, self.width)?;
+        let ts = match self.trigger {
+            None => None,
+            Some(Channel::Ch1) => Some(TS_TI1FP1),
+            Some(Channel::Ch2) => Some(TS_TI2FP2),
+            Some(_) => return Err(CompareError::ConfigError),
+        };
+        if self.trigger.is_some() && self.channel.index() < 2 {
+            return Err(CompareError::ConfigError);
+        }
+
+        timer::enable_clock(self.timer);
+        let tim = timer::regs(self.timer);
+        unsafe {
+            tim.cr1.write(|w| w.bits(CR1_OPM));
+        }
+        let tick_frequency = set_tick_frequency(self.timer, self.tick_frequency)?;
+        configure_output(self.timer, self.channel)?;
+        timer::enable_output(self.timer, self.channel, false);
+        timer::set_output_mode(self.timer, self.channel, OutputMode::Pwm2);
+        timer::set_output_polarity(self.timer, self.channel, self.active_low);
+        timer::set_compare(self.timer, self.channel, self.delay);
+        unsafe {
+            tim.arr.write(|w| w.bits(arr));
+            tim.cnt.write(|w| w.bits(0));
+            tim.egr.write(|w| w.ug().bit(true));
+            tim.sr.write(|w| w.bits(0));
+        }
+
+        if let (Some(input), Some(ts)) = (self.trigger, ts) {
+            let (port, pin) = timer::channel_pin(self.timer, input);
+            GpioConfig::new()
+                .port(port)
+                .pin(pin)
+                .conf(Conf::FloatingIn)
+                .mode(Mode::Input)
+                .configure()
+                .map_err(|_| CompareError::ConfigError)?;
+            // CCxS 01, input on its own pin, rising edge
+            let shift = input.index() * 8;
+            unsafe {
+                tim.ccmr1_input.modify(|r, w| w.bits(r.bits() & !(0xFF << shift) | 0b01 << shift));
+            }
+            timer::set_slave_mode(self.timer, SlaveMode::Trigger, ts);
+        } else {
+            // a previous user may have left a trigger
+            timer::set_slave_mode(self.timer, SlaveMode::Disabled, 0);
+        }
+        timer::enable_output(self.timer, self.channel, true);
+
+        Ok(OnePulse {
+            timer : self.timer,
+            channel : self.channel,
+            tick_frequency,
+        })
+    }
+}
+
+// the last tick of the pulse is the reload value, delay + width can reach
+// 0x10000
+fn pulse_reload(delay : u16, width : u16) -> CompareResult<u32> {
+    if delay == 0 || width == 0 || delay as u32 + width as u32 - 1 > 0xFFFF {
+        return Err(CompareError::InvalidPulse);
+    }
+    Ok(delay as u32 + width as u32 - 1)
+}
+
+pub struct OnePulse {
+    timer : TimerId,
+    channel : Channel,
+    tick_frequency : u32,
+}
+
+impl OnePulse {
+    pub fn tick_frequency(&self) -> u32 {
+        self.tick_frequency
+    }
+
+    pub fn fire(&mut self) -> CompareResult<()> {
+        if self.is_busy() {
+            return Err(CompareError::Busy);
+        }
+        timer::regs(self.timer).cr1.modify(|_, w| w.cen().bit(true));
+        Ok(())
+    }
+
+    // the hardware clears the counter enable at the end of the pulse
+    pub fn is_busy(&self) -> bool {
+        timer::regs(self.timer).cr1.read().cen().bit()
+    }
+
+    // used from the next pulse, both values are loaded right away
+    pub fn set_pulse(&mut self, delay : u16, width : u16) -> CompareResult<()> {
+        let arr = pulse_reload(delay, width)?;
+        if self.is_busy() {
+            return Err(CompareError::Busy);
+        }
+        timer::set_compare(self.timer, self.channel, delay);
+        let tim = timer::regs(self.timer);
+        unsafe {
+            tim.arr.write(|w| w.bits(arr));
+            // ccr is preloaded, load it now instead of after the next pulse
+            tim.egr.write(|w| w.ug().bit(true));
+            tim.sr.write(|w| w.bits(0));
+        }
+        Ok(())
+    }
+
+    pub fn release(self) -> TimerId {
+        let tim = timer::regs(self.timer);
+        timer::set_slave_mode(self.timer, SlaveMode::Disabled, 0);
+        unsafe {
+            tim.cr1.write(|w| w.bits(0));
+            tim.ccer.write(|w| w.bits(0));
+        }
+        self.timer
+    }
+}
+
+pub struct OutputCompareConfig {
+    timer : TimerId,
+    tick_frequency : u32,
+    channels : [bool; 4],
+}
+
+impl OutputCompareConfig {
+    pub fn new(timer : TimerId) -> OutputCompareConfig {
+        OutputCompareConfig {
+            timer,
+            tick_frequency : 1_000_000,
+            channels : [false; 4],
+        }
+    }
+
+    pub fn tick_frequency(mut self, hz : u32) -> OutputCompareConfig {
+        self.tick_frequency = hz;
+        self
+    }
+
+    // drives the channel pin, channels not given only get callbacks
+    pub fn channel(mut self, ch : Channel) -> OutputCompareConfig {
+        self.channels[ch.index()] = true;
+        self
+    }
+
+    // the counter runs freely from 0 to 0xFFFF
+    pub fn configure(&self) -> CompareResult<OutputCompare> {
+        timer::enable_clock(self.timer);
+        let tim = timer::regs(self.timer);
+        unsafe {
+            tim.cr1.write(|w| w.bits(0));
+            tim.dier.write(|w| w.bits(0));
+            tim.ccer.write(|w| w.bits(0));
+            tim.arr.write(|w| w.bits(0xFFFF));
+        }
+        let tick_frequency = set_tick_frequency(self.timer, self.tick_frequency)?;
+
+        let all = [Channel::Ch1, Channel::Ch2, Channel::Ch3, Channel::Ch4];
+        for &ch in all.iter() {
+            // the preload is left off so a new compare value applies at once
+            timer::set_output_mode(self.timer, ch, OutputMode::Frozen);
+            unsafe {
+                let shift = (ch.index() % 2) * 8;
+                match ch {
+                    Channel::Ch1 | Channel::Ch2 => tim.ccmr1_output.modify(|r, w| w.bits(r.bits() & !(1 << (3 + shift)))),
+                    Channel::Ch3 | Channel::Ch4 => tim.ccmr2_output.modify(|r, w| w.bits(r.bits() & !(1 << (3 + shift)))),
+                }
+            }
+            if self.channels[ch.index()] {
+                configure_output(self.timer, ch)?;
+                timer::enable_output(self.timer, ch, true);
+            }
+        }
+
+        interrupt::free(|_| unsafe {
+            CALLBACKS[index(self.timer)] = [None; 4];
+            ARMED[index(self.timer)] = [false; 4];
+        });
+        unsafe {
+            tim.egr.write(|w| w.ug().bit(true));
+            tim.sr.write(|w| w.bits(0));
+            tim.cr1.modify(|_, w| w.cen().bit(true));
+        }
+        enable_interrupt(self.timer);
+
+        Ok(OutputCompare {
+            timer : self.timer,
+            tick_frequency,
+            channels : self.channels,
+        })
+    }
+}
+
+pub struct OutputCompare {
+    timer : TimerId,
+    tick_frequency : u32,
+    channels : [bool; 4],
+}
+
+impl OutputCompare {
+    pub fn tick_frequency(&self) -> u32 {
+        self.tick_frequency
+    }
+
+    pub fn counter(&self) -> u16 {
+        timer::regs(self.timer).cnt.read().bits() as u16
+    }
+
+    // action and callback when the counter reaches count, replaces the
+    // pending one of the channel. the callback runs in the timer interrupt
+    pub fn schedule_at(&mut self, ch : Channel, count : u16, action : CompareAction, cb : Option<fn()>) -> CompareResult<()> {
+        if action != CompareAction::None && !self.channels[ch.index()] {
+            return Err(CompareError::ConfigError);
+        }
+        let id = self.timer;
+        let tim = timer::regs(id);
+        let idx = index(id);
+        interrupt::free(|_| unsafe {
+            tim.dier.modify(|r, w| w.bits(r.bits() & !(DIER_CC1IE << ch.index())));
+            timer::set_compare(id, ch, count);
+            set_mode(id, ch, action.mode());
+            timer::clear_flags(id, SR_CC1IF << ch.index());
+            CALLBACKS[idx][ch.index()] = cb;
+            ARMED[idx][ch.index()] = true;
+            tim.dier.modify(|r, w| w.bits(r.bits() | (DIER_CC1IE << ch.index())));
+        });
+        Ok(())
+    }
+
+    // same as schedule_at, ticks from now. less than 0x10000 ticks
+    pub fn schedule_after(&mut self, ch : Channel, ticks : u16, action : CompareAction, cb : Option<fn()>) -> CompareResult<()> {
+        let count = self.counter().wrapping_add(ticks);
+        self.schedule_at(ch, count, action, cb)
+    }
+
+    pub fn cancel(&mut self, ch : Channel) {
+        let id = self.timer;
+        let tim = timer::regs(id);
+        interrupt::free(|_| unsafe {
+            tim.dier.modify(|r, w| w.bits(r.bits() & !(DIER_CC1IE << ch.index())));
+            set_mode(id, ch, OutputMode::Frozen);
+            CALLBACKS[index(id)][ch.index()] = None;
+            ARMED[index(id)][ch.index()] = false;
+        });
+    }
+
+    pub fn is_pending(&self, ch : Channel) -> bool {
+        let idx = index(self.timer);
+        interrupt::free(|_| unsafe { ARMED[idx][ch.index()] })
+    }
+
+    // sets the output level now, any pending action of the channel is cancelled
+    pub fn force(&mut self, ch : Channel, active : bool) -> CompareResult<()> {
+        if !self.channels[ch.index()] {
+            return Err(CompareError::ConfigError);
+        }
+        self.cancel(ch);
+        set_mode(self.timer, ch, if active { OutputMode::ForceActive } else { OutputMode::ForceInactive });
+        Ok(())
+    }
+
+    pub fn set_polarity(&mut self, ch : Channel, active_low : bool) {
+        timer::set_output_polarity(self.timer, ch, active_low);
+    }
+
+    pub fn release(self) -> TimerId {
+        let tim = timer::regs(self.timer);
+        unsafe {
+            tim.cr1.modify(|_, w| w.cen().bit(false));
+            tim.dier.write(|w| w.bits(0));
+            tim.ccer.write(|w| w.bits(0));
+        }
+        interrupt::free(|_| unsafe {
+            CALLBACKS[index(self.timer)] = [None; 4];
+            ARMED[index(self.timer)] = [false; 4];
+        });
+        self.timer
+    }
+}
+
+// OCxM only, the preload stays off
+fn set_mode(id : TimerId, ch : Channel, mode : OutputMode) {
+    let tim = timer::regs(id);
+    let shift = (ch.index() % 2) * 8 + 4;
+    let mask = 0b111 << shift;
+    let field = mode.as_code() << shift;
+    unsafe {
+        match ch {
+            Channel::Ch1 | Channel::Ch2 => tim.ccmr1_output.modify(|r, w| w.bits(r.bits() & !mask | field)),
+            Channel::Ch3 | Channel::Ch4 => tim.ccmr2_output.modify(|r, w| w.bits(r.bits() & !mask | field)),
+        }
+    }
+}
+
+// called from the timer interrupts, runs the due scheduled callbacks
+pub fn handler(id : TimerId) {
+    let idx = index(id);
+    let tim = timer::regs(id);
+    let sr = tim.sr.read().bits();
+    let all = [Channel::Ch1, Channel::Ch2, Channel::Ch3, Channel::Ch4];
+    for &ch in all.iter() {
+        let i = ch.index();
+        unsafe {
+            if !ARMED[idx][i] || sr & (SR_CC1IF << i) == 0 {
+                continue;
+            }
+            timer::clear_flags(id, SR_CC1IF << i);
+            tim.dier.modify(|r, w| w.bits(r.bits() & !(DIER_CC1IE << i)));
+            ARMED[idx][i] = false;
+            if let Some(cb) = CALLBACKS[idx][i].take() {
+                cb();
+            }
+        }
+    }
+}
@@ -1,32 +1,86 @@
 use cortex_m::peripheral::{SYST, SystClkSource};
 use cortex_m::asm;
+use cortex_m::interrupt;
 use clocks::*;
+use timing::time::{Instant, Duration};
+use timing::soft_timer;
 
+// milliseconds since initialize, systick keeps running between delays.
+// TICKS wraps, TICKS_HIGH counts the wraps for the 64 bits uptime
 static mut TICKS : u32 = 0;
+static mut TICKS_HIGH : u32 = 0;
+static mut CYCLES_PER_US : u32 = 8;
 
+const SYST_CSR_ENABLE : u32 = 1 << 0;
+
+// call again after changing the clock configuration
 pub fn initialize() {
-    let systick_freq = ClockConfig::get_speeds().sys_clk;
+    // the core clock source of systick is hclk
+    let systick_freq = ClockConfig::get_speeds().ahb_clk;
     unsafe {
+        CYCLES_PER_US = systick_freq / 1_000_000;
         (*SYST.get()).set_clock_source(SystClkSource::Core);
-        (*SYST.get()).set_reload(systick_freq / 1000);
+        // the counter goes from reload down to 0, reload + 1 cycles per tick
+        (*SYST.get()).set_reload(systick_freq / 1000 - 1);
+        (*SYST.get()).clear_current();
+        (*SYST.get()).enable_interrupt();
+        (*SYST.get()).enable_counter();
     }
 }
 
+// monotonic time in milliseconds, doesn't wrap
+pub fn now() -> u64 {
+    // both words have to come from the same tick
+    interrupt::free(|_| unsafe { (TICKS_HIGH as u64) << 32 | TICKS as u64 })
+}
+
+// wrapping 32 bits tick, cheaper than now() and enough for timeouts
+pub fn instant() -> Instant {
+    Instant::from_ticks(unsafe { TICKS })
+}
+
+pub fn elapsed(since : Instant) -> Duration {
+    instant().duration_since(since)
+}
+
 pub fn ms(time : u32) {
+    let start = instant();
+    let wait = Duration::from_ms(time);
+    while !start.has_elapsed(wait, instant()) {
+        asm::wfi();
+    }
+}
+
+// busy wait on the systick current value, the interrupt may fire in between.
+// returns right away if systick isn't running, i.e. before initialize
+pub fn us(time : u32) {
     unsafe {
-        (*SYST.get()).enable_interrupt();
-        (*SYST.get()).enable_counter();
-        let start = TICKS;
-        while (TICKS - start) < time {
-            asm::wfi();
+        if (*SYST.get()).csr.read() & SYST_CSR_ENABLE == 0 {
+            return;
+        }
+        let target = time as u64 * CYCLES_PER_US as u64;
+        let reload = (*SYST.get()).get_reload();
+        let mut elapsed : u64 = 0;
+        let mut prev = (*SYST.get()).get_current();
+        while elapsed < target {
+            let cur = (*SYST.get()).get_current();
+            // the counter counts down and restarts from reload
+            if cur <= prev {
+                elapsed += (prev - cur) as u64;
+            } else {
+                elapsed += (prev + (reload + 1 - cur)) as u64;
+            }
+            prev = cur;
         }
-        (*SYST.get()).disable_counter();
-        (*SYST.get()).disable_interrupt();
     }
 }
 
 pub fn ticks() {
     unsafe {
-        TICKS += 1;
+        TICKS = TICKS.wrapping_add(1);
+        if TICKS == 0 {
+            TICKS_HIGH = TICKS_HIGH.wrapping_add(1);
+        }
     }
-}
\ No newline at end of file
+    soft_timer::tick(instant());
+}
@@ -1,3 +1,6 @@
-
+pub mod compare;
 pub mod delay;
-pub mod timer;
\ No newline at end of file
+pub mod soft_timer;
+pub mod time;
+pub mod timer;
+pub mod wheel;
@@ -0,0 +1,41 @@
+use cortex_m::interrupt;
+use timing::time::{Instant, Duration};
+use timing::delay;
+
+pub use timing::wheel::{TimerWheel, TimerHandle, SoftTimerError, SoftTimerResult, Expired, MAX_TIMERS};
+use timing::wheel::EMPTY_WHEEL;
+
+// service driven by the systick, callbacks run in the systick exception
+static mut WHEEL : TimerWheel = EMPTY_WHEEL;
+
+pub fn one_shot(after : Duration, cb : fn()) -> SoftTimerResult<TimerHandle> {
+    let now = delay::instant();
+    interrupt::free(|_| unsafe { WHEEL.one_shot(now, after, cb) })
+}
+
+pub fn periodic(period : Duration, cb : fn()) -> SoftTimerResult<TimerHandle> {
+    let now = delay::instant();
+    interrupt::free(|_| unsafe { WHEEL.periodic(now, period, cb) })
+}
+
+pub fn cancel(h : TimerHandle) -> SoftTimerResult<()> {
+    interrupt::free(|_| unsafe { WHEEL.cancel(h) })
+}
+
+pub fn reschedule(h : TimerHandle, after : Duration) -> SoftTimerResult<()> {
+    let now = delay::instant();
+    interrupt::free(|_| unsafe { WHEEL.reschedule(h, now, after) })
+}
+
+pub fn is_active(h : TimerHandle) -> bool {
+    interrupt::free(|_| unsafe { WHEEL.is_active(h) })
+}
+
+pub fn tick(now : Instant) {
+    let expired = interrupt::free(|_| unsafe { WHEEL.expire(now) });
+    for cb in expired.iter() {
+        if let Some(cb) = *cb {
+            cb();
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use core::ops::{Add, Sub};
+
+// millisecond resolution, up to ~49 days. the arithmetic saturates instead
+// of wrapping, use the checked versions to detect it
+#[derive(Eq, PartialEq, Ord, PartialOrd, Copy, Clone, Debug)]
+pub struct Duration {
+    ms : u32,
+}
+
+impl Duration {
+    pub fn from_ms(ms : u32) -> Duration {
+        Duration { ms }
+    }
+
+    pub fn from_secs(s : u32) -> Duration {
+        Duration { ms : s.saturating_mul(1000) }
+    }
+
+    pub fn as_ms(&self) -> u32 {
+        self.ms
+    }
+
+    pub fn checked_add(&self, other : Duration) -> Option<Duration> {
+        self.ms.checked_add(other.ms).map(Duration::from_ms)
+    }
+
+    pub fn checked_sub(&self, other : Duration) -> Option<Duration> {
+        self.ms.checked_sub(other.ms).map(Duration::from_ms)
+    }
+}
+
+impl Add for Duration {
+    type Output = Duration;
+
+    fn add(self, other : Duration) -> Duration {
+        Duration { ms : self.ms.saturating_add(other.ms) }
+    }
+}
+
+// zero when other is longer
+impl Sub for Duration {
+    type Output = Duration;
+
+    fn sub(self, other : Duration) -> Duration {
+        Duration { ms : self.ms.saturating_sub(other.ms) }
+    }
+}
+
+// point in time on the millisecond tick counter, which wraps after ~49 days.
+// comparisons and differences are done modulo 2^32, they are valid as long as
+// the instants are less than ~24 days apart
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub struct Instant {
+    ticks : u32,
+}
+
+impl Instant {
+    pub fn from_ticks(ticks : u32) -> Instant {
+        Instant { ticks }
+    }
+
+    pub fn ticks(&self) -> u32 {
+        self.ticks
+    }
+
+    pub fn duration_since(&self, earlier : Instant) -> Duration {
+        Duration::from_ms(self.ticks.wrapping_sub(earlier.ticks))
+    }
+
+    pub fn is_after(&self, other : Instant) -> bool {
+        (self.ticks.wrapping_sub(other.ticks) as i32) > 0
+    }
+
+    pub fn is_before(&self, other : Instant) -> bool {
+        other.is_after(*self)
+    }
+
+    // true once at least d has passed since self
+    pub fn has_elapsed(&self, d : Duration, now : Instant) -> bool {
+        now.duration_since(*self) >= d
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, d : Duration) -> Instant {
+        Instant { ticks : self.ticks.wrapping_add(d.ms) }
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, d : Duration) -> Instant {
+        Instant { ticks : self.ticks.wrapping_sub(d.ms) }
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier : Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duration_saturates() {
+        assert_eq!(Duration::from_secs(5).as_ms(), 5000);
+        assert_eq!(Duration::from_secs(0xFFFF_FFFF).as_ms(), 0xFFFF_FFFF);
+        let max = Duration::from_ms(0xFFFF_FFFF);
+        assert_eq!(max + Duration::from_ms(1), max);
+        assert_eq!(Duration::from_ms(1) - Duration::from_ms(2), Duration::from_ms(0));
+        assert_eq!(max.checked_add(Duration::from_ms(1)), None);
+        assert_eq!(Duration::from_ms(1).checked_sub(Duration::from_ms(2)), None);
+        assert_eq!(Duration::from_ms(3).checked_sub(Duration::from_ms(2)), Some(Duration::from_ms(1)));
+    }
+
+    #[test]
+    fn is_after_across_wrap() {
+        let before = Instant::from_ticks(0xFFFF_FFF0);
+        let after = Instant::from_ticks(0x0000_0010);
+        assert!(after.is_after(before));
+        assert!(!before.is_after(after));
+        assert!(before.is_before(after));
+        assert!(!before.is_after(before));
+        assert_eq!(before + Duration::from_ms(0x20), after);
+        assert_eq!(after - Duration::from_ms(0x20), before);
+    }
+
+    #[test]
+    fn is_after_limit() {
+        let t = Instant::from_ticks(0x8000_0000);
+        assert!((t + Duration::from_ms(0x7FFF_FFFF)).is_after(t));
+        // half the counter range apart, the order can't be told anymore
+        assert!(!(t + Duration::from_ms(0x8000_0000)).is_after(t));
+    }
+
+    #[test]
+    fn duration_since_across_wrap() {
+        let start = Instant::from_ticks(0xFFFF_FFFE);
+        let end = Instant::from_ticks(3);
+        assert_eq!(end.duration_since(start).as_ms(), 5);
+        assert_eq!((end - start).as_ms(), 5);
+        assert_eq!(start.duration_since(start).as_ms(), 0);
+    }
+
+    // same loop as delay::ms, with the tick counter wrapping while waiting
+    #[test]
+    fn ms_wait_across_wrap() {
+        for &first in [0u32, 0xFFFF_FF00, 0xFFFF_FFFF].iter() {
+            let start = Instant::from_ticks(first);
+            let wait = Duration::from_ms(0x200);
+            let mut now = start;
+            let mut ticks = 0;
+            while !start.has_elapsed(wait, now) {
+                now = Instant::from_ticks(now.ticks().wrapping_add(1));
+                ticks += 1;
+            }
+            assert_eq!(ticks, 0x200);
+        }
+    }
+}
@@ -1 +1,543 @@
+use stm32f103xx::{TIM1, TIM2, TIM3, TIM4, RCC, Interrupt};
+use stm32f103xx::tim2;
+use cortex_m::peripheral::NVIC;
+use clocks::*;
+use gpio::{Port, Pin};
+use capture;
+use encoder;
+use timing::compare;
 
+#[derive(Debug)]
+pub enum TimerError {
+    InvalidFrequency,
+    InvalidTrigger,
+    // the count mode can't change while the counter runs
+    Running,
+}
+
+pub type TimerResult<T> = Result<T, TimerError>;
+
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum TimerId {
+    // advanced timer on apb2
+    Tim1,
+    // general purpose timers on apb1
+    Tim2,
+    Tim3,
+    Tim4,
+}
+
+impl TimerId {
+    fn index(&self) -> usize {
+        match *self {
+            TimerId::Tim1 => 0,
+            TimerId::Tim2 => 1,
+            TimerId::Tim3 => 2,
+            TimerId::Tim4 => 3,
+        }
+    }
+
+    // update interrupt
+    fn interrupt(&self) -> Interrupt {
+        match *self {
+            TimerId::Tim1 => Interrupt::TIM1_UP,
+            TimerId::Tim2 => Interrupt::TIM2,
+            TimerId::Tim3 => Interrupt::TIM3,
+            TimerId::Tim4 => Interrupt::TIM4,
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum Channel {
+    Ch1,
+    Ch2,
+    Ch3,
+    Ch4,
+}
+
+impl Channel {
+    pub fn index(&self) -> usize {
+        match *self {
+            Channel::Ch1 => 0,
+            Channel::Ch2 => 1,
+            Channel::Ch3 => 2,
+            Channel::Ch4 => 3,
+        }
+    }
+}
+
+// OCxM field of the capture/compare mode registers
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum OutputMode {
+    Frozen = 0b000,
+    ActiveOnMatch = 0b001,
+    InactiveOnMatch = 0b010,
+    Toggle = 0b011,
+    ForceInactive = 0b100,
+    ForceActive = 0b101,
+    Pwm1 = 0b110,
+    Pwm2 = 0b111,
+}
+
+impl OutputMode {
+    pub fn as_code(&self) -> u32 {
+        (*self as u32)
+    }
+}
+
+// in the center-aligned modes the counter counts up then down, the
+// number tells when the compare interrupt flags of the output channels
+// are set : 1 when counting down, 2 when counting up, 3 both
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum CountMode {
+    Up,
+    Down,
+    CenterAligned1,
+    CenterAligned2,
+    CenterAligned3,
+}
+
+impl CountMode {
+    pub fn is_center_aligned(&self) -> bool {
+        match *self {
+            CountMode::Up | CountMode::Down => false,
+            _ => true,
+        }
+    }
+
+    // DIR and CMS bits of CR1
+    fn cr1_bits(&self) -> u32 {
+        match *self {
+            CountMode::Up => 0,
+            CountMode::Down => 1 << 4,
+            CountMode::CenterAligned1 => 0b01 << 5,
+            CountMode::CenterAligned2 => 0b10 << 5,
+            CountMode::CenterAligned3 => 0b11 << 5,
+        }
+    }
+}
+
+// event sent on TRGO, MMS field of CR2
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum MasterMode {
+    Reset = 0b000,
+    // counter enable, starts the slaves with the master
+    Enable = 0b001,
+    Update = 0b010,
+    ComparePulse = 0b011,
+    Compare1 = 0b100,
+    Compare2 = 0b101,
+    Compare3 = 0b110,
+    Compare4 = 0b111,
+}
+
+// reaction to the trigger input, SMS field of SMCR
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum SlaveMode {
+    Disabled = 0b000,
+    // quadrature encoder, counting on TI1 edges, TI2 edges or both
+    Encoder1 = 0b001,
+    Encoder2 = 0b010,
+    Encoder3 = 0b011,
+    Reset = 0b100,
+    Gated = 0b101,
+    Trigger = 0b110,
+    ExternalClock = 0b111,
+}
+
+pub const SR_UIF : u32 = 1 << 0;
+
+// update callbacks, called from the timers interrupts
+static mut CALLBACKS : [Option<fn()>; 4] = [None; 4];
+
+pub struct TimerConfig {
+    id : TimerId,
+    frequency : u32,
+    count_mode : CountMode,
+    callback : Option<fn()>,
+}
+
+impl TimerConfig {
+    pub fn new(id : TimerId) -> TimerConfig {
+        TimerConfig {
+            id,
+            frequency : 1000,
+            count_mode : CountMode::Up,
+            callback : None,
+        }
+    }
+
+    // the frequency stays the update frequency, in the center-aligned
+    // modes there is an update at both ends of the count
+    pub fn count_mode(mut self, mode : CountMode) -> TimerConfig {
+        self.count_mode = mode;
+        self
+    }
+
+    // update events per second
+    pub fn frequency(mut self, hz : u32) -> TimerConfig {
+        self.frequency = hz;
+        self
+    }
+
+    pub fn callback(mut self, cb : Option<fn()>) -> TimerConfig {
+        self.callback = cb;
+        self
+    }
+
+    // the timer is configured but not started
+    pub fn configure(&self) -> TimerResult<Timer> {
+        let (psc, arr) = prescalers(clock(self.id), self.frequency)?;
+        enable_clock(self.id);
+        let tim = regs(self.id);
+        unsafe {
+            tim.cr1.write(|w| w.bits(self.count_mode.cr1_bits()));
+            tim.psc.write(|w| w.bits(psc as u32));
+            tim.arr.write(|w| w.bits(arr as u32));
+            tim.cr1.modify(|_, w| w.arpe().bit(true));
+            // load psc and arr now, without raising the update flag
+            tim.cr1.modify(|_, w| w.urs().bit(true));
+            tim.egr.write(|w| w.ug().bit(true));
+            tim.sr.write(|w| w.bits(0));
+            tim.cr1.modify(|_, w| w.urs().bit(false));
+
+            CALLBACKS[self.id.index()] = self.callback;
+            if self.callback.is_some() {
+                tim.dier.modify(|_, w| w.uie().bit(true));
+                (*NVIC.get()).enable(self.id.interrupt());
+            }
+        }
+        Ok(Timer {
+            id : self.id,
+            psc,
+            arr,
+        })
+    }
+}
+
+pub struct Timer {
+    id : TimerId,
+    psc : u16,
+    arr : u16,
+}
+
+impl Timer {
+    pub fn id(&self) -> TimerId {
+        self.id
+    }
+
+    pub fn prescaler(&self) -> u16 {
+        self.psc
+    }
+
+    pub fn auto_reload(&self) -> u16 {
+        self.arr
+    }
+
+    pub fn start(&mut self) {
+        regs(self.id).cr1.modify(|_, w| w.cen().bit(true));
+    }
+
+    pub fn stop(&mut self) {
+        regs(self.id).cr1.modify(|_, w| w.cen().bit(false));
+    }
+
+    pub fn is_running(&self) -> bool {
+        regs(self.id).cr1.read().cen().bit()
+    }
+
+    pub fn counter(&self) -> u16 {
+        regs(self.id).cnt.read().bits() as u16
+    }
+
+    // takes effect at the next update event
+    pub fn set_frequency(&mut self, hz : u32) -> TimerResult<()> {
+        let (psc, arr) = prescalers(clock(self.id), hz)?;
+        let tim = regs(self.id);
+        unsafe {
+            tim.psc.write(|w| w.bits(psc as u32));
+            tim.arr.write(|w| w.bits(arr as u32));
+        }
+        self.psc = psc;
+        self.arr = arr;
+        Ok(())
+    }
+
+    // polled use, returns true and clears the flag once per update event
+    pub fn has_elapsed(&mut self) -> bool {
+        let tim = regs(self.id);
+        if tim.sr.read().uif().bit() {
+            clear_flags(self.id, SR_UIF);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn set_callback(&mut self, cb : Option<fn()>) {
+        let tim = regs(self.id);
+        unsafe {
+            CALLBACKS[self.id.index()] = cb;
+            tim.dier.modify(|_, w| w.uie().bit(cb.is_some()));
+            if cb.is_some() {
+                (*NVIC.get()).enable(self.id.interrupt());
+            }
+        }
+    }
+
+    pub fn release(self) -> TimerId {
+        self.stop_and_clear();
+        self.id
+    }
+
+    fn stop_and_clear(&self) {
+        let tim = regs(self.id);
+        unsafe {
+            tim.cr1.modify(|_, w| w.cen().bit(false));
+            tim.dier.modify(|_, w| w.uie().bit(false));
+            CALLBACKS[self.id.index()] = None;
+        }
+    }
+}
+
+// input clock of the timer counter
+pub fn clock(id : TimerId) -> u32 {
+    let speeds = ClockConfig::get_speeds();
+    match id {
+        TimerId::Tim1 => speeds.apb2_tim_clk,
+        _ => speeds.apb1_tim_clk,
+    }
+}
+
+pub fn enable_clock(id : TimerId) {
+    unsafe {
+        match id {
+            TimerId::Tim1 => (*RCC.get()).apb2enr.modify(|_, w| w.tim1en().bit(true)),
+            TimerId::Tim2 => (*RCC.get()).apb1enr.modify(|_, w| w.tim2en().bit(true)),
+            TimerId::Tim3 => (*RCC.get()).apb1enr.modify(|_, w| w.tim3en().bit(true)),
+            TimerId::Tim4 => (*RCC.get()).apb1enr.modify(|_, w| w.tim4en().bit(true)),
+        }
+    }
+}
+
+// the registers shared by all timers have the same layout, tim1 only adds
+// rcr and bdtr which have to be reached through TIM1 directly
+pub fn regs(id : TimerId) -> &'static tim2::RegisterBlock {
+    unsafe {
+        match id {
+            TimerId::Tim1 => &*(TIM1.get() as *const tim2::RegisterBlock),
+            TimerId::Tim2 => &*TIM2.get(),
+            TimerId::Tim3 => &*(TIM3.get() as *const tim2::RegisterBlock),
+            TimerId::Tim4 => &*(TIM4.get() as *const tim2::RegisterBlock),
+        }
+    }
+}
+
+// the status flags are cleared by writing 0, the other ones are written
+// with 1 so a flag raised since the last read isn't lost
+pub fn clear_flags(id : TimerId, mask : u32) {
+    unsafe {
+        regs(id).sr.write(|w| w.bits(0x1EFF & !mask));
+    }
+}
+
+// channels pins without remap
+pub fn channel_pin(id : TimerId, ch : Channel) -> (Port, Pin) {
+    match (id, ch) {
+        (TimerId::Tim1, _) => (Port::A, Pin(8 + ch.index() as u32)),
+        (TimerId::Tim2, _) => (Port::A, Pin(ch.index() as u32)),
+        (TimerId::Tim3, Channel::Ch1) => (Port::A, Pin(6)),
+        (TimerId::Tim3, Channel::Ch2) => (Port::A, Pin(7)),
+        (TimerId::Tim3, Channel::Ch3) => (Port::B, Pin(0)),
+        (TimerId::Tim3, Channel::Ch4) => (Port::B, Pin(1)),
+        (TimerId::Tim4, _) => (Port::B, Pin(6 + ch.index() as u32)),
+    }
+}
+
+// output compare mode with the ccr preload enabled, the channel becomes an output
+pub fn set_output_mode(id : TimerId, ch : Channel, mode : OutputMode) {
+    let tim = regs(id);
+    // ch1 and ch3 use the low byte of their ccmr, ch2 and ch4 the high one
+    let shift = (ch.index() % 2) * 8;
+    let field = (mode.as_code() << 4 | 1 << 3) << shift;
+    let mask = 0xFF << shift;
+    unsafe {
+        match ch {
+            Channel::Ch1 | Channel::Ch2 => tim.ccmr1_output.modify(|r, w| w.bits(r.bits() & !mask | field)),
+            Channel::Ch3 | Channel::Ch4 => tim.ccmr2_output.modify(|r, w| w.bits(r.bits() & !mask | field)),
+        }
+    }
+}
+
+pub fn set_compare(id : TimerId, ch : Channel, value : u16) {
+    let tim = regs(id);
+    unsafe {
+        match ch {
+            Channel::Ch1 => tim.ccr1.write(|w| w.bits(value as u32)),
+            Channel::Ch2 => tim.ccr2.write(|w| w.bits(value as u32)),
+            Channel::Ch3 => tim.ccr3.write(|w| w.bits(value as u32)),
+            Channel::Ch4 => tim.ccr4.write(|w| w.bits(value as u32)),
+        }
+    }
+}
+
+pub fn compare(id : TimerId, ch : Channel) -> u16 {
+    let tim = regs(id);
+    let v = match ch {
+        Channel::Ch1 => tim.ccr1.read().bits(),
+        Channel::Ch2 => tim.ccr2.read().bits(),
+        Channel::Ch3 => tim.ccr3.read().bits(),
+        Channel::Ch4 => tim.ccr4.read().bits(),
+    };
+    v as u16
+}
+
+// CCxE
+pub fn enable_output(id : TimerId, ch : Channel, enable : bool) {
+    let bit = 1 << (ch.index() * 4);
+    unsafe {
+        regs(id).ccer.modify(|r, w| w.bits(if enable { r.bits() | bit } else { r.bits() & !bit }));
+    }
+}
+
+pub fn output_enabled(id : TimerId, ch : Channel) -> bool {
+    regs(id).ccer.read().bits() & (1 << (ch.index() * 4)) != 0
+}
+
+// CCxP, the output is inverted when active low
+pub fn set_output_polarity(id : TimerId, ch : Channel, active_low : bool) {
+    let bit = 1 << (ch.index() * 4 + 1);
+    unsafe {
+        regs(id).ccer.modify(|r, w| w.bits(if active_low { r.bits() | bit } else { r.bits() & !bit }));
+    }
+}
+
+pub fn set_count_mode(id : TimerId, mode : CountMode) -> TimerResult<()> {
+    let tim = regs(id);
+    if tim.cr1.read().cen().bit() {
+        return Err(TimerError::Running);
+    }
+    unsafe {
+        tim.cr1.modify(|r, w| w.bits(r.bits() & !(0b111 << 4) | mode.cr1_bits()));
+    }
+    Ok(())
+}
+
+pub fn count_mode(id : TimerId) -> CountMode {
+    let cr1 = regs(id).cr1.read().bits();
+    match (cr1 >> 5) & 0b11 {
+        0b01 => CountMode::CenterAligned1,
+        0b10 => CountMode::CenterAligned2,
+        0b11 => CountMode::CenterAligned3,
+        _ => if cr1 & (1 << 4) != 0 { CountMode::Down } else { CountMode::Up },
+    }
+}
+
+pub fn set_master_mode(id : TimerId, mode : MasterMode) {
+    unsafe {
+        regs(id).cr2.modify(|r, w| w.bits(r.bits() & !(0b111 << 4) | (mode as u32) << 4));
+    }
+}
+
+// trigger is the TS field, 0 to 3 for ITR0 to ITR3
+pub fn set_slave_mode(id : TimerId, mode : SlaveMode, trigger : u32) {
+    unsafe {
+        regs(id).smcr.modify(|r, w| {
+            w.bits(r.bits() & !(0b111 << 4 | 0b111) | (trigger & 0b111) << 4 | mode as u32)
+        });
+    }
+}
+
+// ITRx input of slave connected to the TRGO of master, from the internal
+// trigger connection tables of the reference manual. the entries for TIM5
+// and TIM8 are missing on the F103xB
+pub fn internal_trigger(slave : TimerId, master : TimerId) -> Option<u32> {
+    match (slave, master) {
+        (TimerId::Tim1, TimerId::Tim2) => Some(1),
+        (TimerId::Tim1, TimerId::Tim3) => Some(2),
+        (TimerId::Tim1, TimerId::Tim4) => Some(3),
+        (TimerId::Tim2, TimerId::Tim1) => Some(0),
+        (TimerId::Tim2, TimerId::Tim3) => Some(2),
+        (TimerId::Tim2, TimerId::Tim4) => Some(3),
+        (TimerId::Tim3, TimerId::Tim1) => Some(0),
+        (TimerId::Tim3, TimerId::Tim2) => Some(1),
+        (TimerId::Tim3, TimerId::Tim4) => Some(3),
+        (TimerId::Tim4, TimerId::Tim1) => Some(0),
+        (TimerId::Tim4, TimerId::Tim2) => Some(1),
+        (TimerId::Tim4, TimerId::Tim3) => Some(2),
+        _ => None,
+    }
+}
+
+// the slaves are started by the master counter enable, they have to be
+// stopped before and the master started last. the counters then run in lockstep as long as
+// they share the same clock, prescaler and period
+pub fn synchronize(master : TimerId, slaves : &[TimerId]) -> TimerResult<()> {
+    for &s in slaves {
+        if internal_trigger(s, master).is_none() {
+            return Err(TimerError::InvalidTrigger);
+        }
+    }
+    set_master_mode(master, MasterMode::Enable);
+    for &s in slaves {
+        if let Some(itr) = internal_trigger(s, master) {
+            set_slave_mode(s, SlaveMode::Trigger, itr);
+        }
+    }
+    Ok(())
+}
+
+// smallest prescaler giving the update frequency, so that arr keeps the
+// best resolution. arr stays below 0xFFFF so a period always fits in 16 bits.
+// returns (psc, arr) register values
+pub fn prescalers(tim_clk : u32, hz : u32) -> TimerResult<(u16, u16)> {
+    if hz == 0 || hz > tim_clk / 2 {
+        return Err(TimerError::InvalidFrequency);
+    }
+    let ticks = tim_clk / hz;
+    let psc = ticks / 0x1_0000;
+    if psc > 0xFFFF {
+        return Err(TimerError::InvalidFrequency);
+    }
+    let arr = ticks / (psc + 1) - 1;
+    Ok((psc as u16, arr as u16))
+}
+
+fn handler(id : TimerId) {
+    let tim = regs(id);
+    // the capture channels and the encoders have their own flags, they
+    // also count the update events so they have to see the flag before it
+    // is cleared
+    capture::handler(id);
+    encoder::handler(id);
+    compare::handler(id);
+    if tim.sr.read().uif().bit() {
+        clear_flags(id, SR_UIF);
+        unsafe {
+            if let Some(cb) = CALLBACKS[id.index()] {
+                cb();
+            }
+        }
+    }
+}
+
+pub fn tim1_up_handler() {
+    handler(TimerId::Tim1);
+}
+
+pub fn tim1_cc_handler() {
+    handler(TimerId::Tim1);
+}
+
+pub fn tim2_handler() {
+    handler(TimerId::Tim2);
+}
+
+pub fn tim3_handler() {
+    handler(TimerId::Tim3);
+}
+
+pub fn tim4_handler() {
+    handler(TimerId::Tim4);
+}
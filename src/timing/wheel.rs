@@ -0,0 +1,259 @@
+use timing::time::{Instant, Duration};
+
+pub const MAX_TIMERS : usize = 8;
+
+#[derive(Debug)]
+pub enum SoftTimerError {
+    NoFreeSlot,
+    InvalidHandle,
+    ZeroPeriod,
+}
+
+pub type SoftTimerResult<T> = Result<T, SoftTimerError>;
+
+// the generation makes handles of cancelled or expired one-shot timers
+// invalid once their slot is reused
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub struct TimerHandle {
+    slot : usize,
+    generation : u16,
+}
+
+#[derive(Copy, Clone)]
+struct Slot {
+    callback : Option<fn()>,
+    // tick of the next expiry
+    deadline : u32,
+    // 0 for one-shot timers
+    period : u32,
+    generation : u16,
+}
+
+const EMPTY_SLOT : Slot = Slot {
+    callback : None,
+    deadline : 0,
+    period : 0,
+    generation : 0,
+};
+
+// callbacks due at one tick, in slot order
+pub type Expired = [Option<fn()>; MAX_TIMERS];
+
+// fixed number of slots, no hardware access so it can be driven by hand
+pub struct TimerWheel {
+    slots : [Slot; MAX_TIMERS],
+}
+
+pub const EMPTY_WHEEL : TimerWheel = TimerWheel {
+    slots : [EMPTY_SLOT; MAX_TIMERS],
+};
+
+impl TimerWheel {
+    pub fn new() -> TimerWheel {
+        EMPTY_WHEEL
+    }
+
+    pub fn one_shot(&mut self, now : Instant, delay : Duration, cb : fn()) -> SoftTimerResult<TimerHandle> {
+        self.add(now, delay, 0, cb)
+    }
+
+    // first expiry one period after now
+    pub fn periodic(&mut self, now : Instant, period : Duration, cb : fn()) -> SoftTimerResult<TimerHandle> {
+        if period.as_ms() == 0 {
+            return Err(SoftTimerError::ZeroPeriod);
+        }
+        self.add(now, period, period.as_ms(), cb)
+    }
+
+    pub fn cancel(&mut self, h : TimerHandle) -> SoftTimerResult<()> {
+        self.check(h)?;
+        self.free(h.slot);
+        Ok(())
+    }
+
+    // next expiry after delay from now, a periodic timer keeps its period
+    pub fn reschedule(&mut self, h : TimerHandle, now : Instant, delay : Duration) -> SoftTimerResult<()> {
+        self.check(h)?;
+        self.slots[h.slot].deadline = (now + delay).ticks();
+        Ok(())
+    }
+
+    pub fn is_active(&self, h : TimerHandle) -> bool {
+        self.check(h).is_ok()
+    }
+
+    // removes the expired one-shot timers and moves the periodic ones to their
+    // next deadline. the callbacks are returned instead of being called so
+    // they can touch the wheel themselves
+    pub fn expire(&mut self, now : Instant) -> Expired {
+        let mut expired = [None; MAX_TIMERS];
+        for i in 0..MAX_TIMERS {
+            let slot = self.slots[i];
+            if slot.callback.is_none() || Instant::from_ticks(slot.deadline).is_after(now) {
+                continue;
+            }
+            expired[i] = slot.callback;
+            if slot.period == 0 {
+                self.free(i);
+            } else {
+                let mut next = Instant::from_ticks(slot.deadline) + Duration::from_ms(slot.period);
+                // skip the missed periods instead of firing in a burst
+                if !next.is_after(now) {
+                    next = now + Duration::from_ms(slot.period);
+                }
+                self.slots[i].deadline = next.ticks();
+            }
+        }
+        expired
+    }
+
+    fn add(&mut self, now : Instant, delay : Duration, period : u32, cb : fn()) -> SoftTimerResult<TimerHandle> {
+        for i in 0..MAX_TIMERS {
+            if self.slots[i].callback.is_none() {
+                let generation = self.slots[i].generation;
+                self.slots[i] = Slot {
+                    callback : Some(cb),
+                    deadline : (now + delay).ticks(),
+                    period,
+                    generation,
+                };
+                return Ok(TimerHandle {
+                    slot : i,
+                    generation,
+                });
+            }
+        }
+        Err(SoftTimerError::NoFreeSlot)
+    }
+
+    fn free(&mut self, slot : usize) {
+        self.slots[slot].callback = None;
+        self.slots[slot].generation = self.slots[slot].generation.wrapping_add(1);
+    }
+
+    fn check(&self, h : TimerHandle) -> SoftTimerResult<()> {
+        if h.slot >= MAX_TIMERS {
+            return Err(SoftTimerError::InvalidHandle);
+        }
+        let slot = &self.slots[h.slot];
+        if slot.callback.is_none() || slot.generation != h.generation {
+            return Err(SoftTimerError::InvalidHandle);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cb() {}
+
+    fn at(ticks : u32) -> Instant {
+        Instant::from_ticks(ticks)
+    }
+
+    fn ms(ms : u32) -> Duration {
+        Duration::from_ms(ms)
+    }
+
+    #[test]
+    fn one_shot_fires_once() {
+        let mut wheel = TimerWheel::new();
+        let h = wheel.one_shot(at(100), ms(10), cb).unwrap();
+        assert!(wheel.expire(at(109))[h.slot].is_none());
+        assert!(wheel.is_active(h));
+        assert!(wheel.expire(at(110))[h.slot].is_some());
+        assert!(!wheel.is_active(h));
+        assert!(wheel.expire(at(120))[h.slot].is_none());
+    }
+
+    #[test]
+    fn periodic_fires_every_period() {
+        let mut wheel = TimerWheel::new();
+        let h = wheel.periodic(at(0), ms(5), cb).unwrap();
+        let mut fired = 0;
+        for t in 1..21 {
+            if wheel.expire(at(t))[h.slot].is_some() {
+                assert_eq!(t % 5, 0);
+                fired += 1;
+            }
+        }
+        assert_eq!(fired, 4);
+        assert!(wheel.is_active(h));
+    }
+
+    #[test]
+    fn periodic_skips_missed_periods() {
+        let mut wheel = TimerWheel::new();
+        let h = wheel.periodic(at(0), ms(5), cb).unwrap();
+        assert!(wheel.expire(at(17))[h.slot].is_some());
+        assert!(wheel.expire(at(21))[h.slot].is_none());
+        assert!(wheel.expire(at(22))[h.slot].is_some());
+    }
+
+    #[test]
+    fn zero_period_is_rejected() {
+        let mut wheel = TimerWheel::new();
+        assert!(wheel.periodic(at(0), ms(0), cb).is_err());
+    }
+
+    #[test]
+    fn cancel_stops_the_timer() {
+        let mut wheel = TimerWheel::new();
+        let h = wheel.one_shot(at(0), ms(10), cb).unwrap();
+        wheel.cancel(h).unwrap();
+        assert!(!wheel.is_active(h));
+        assert!(wheel.expire(at(10)).iter().all(|e| e.is_none()));
+        assert!(wheel.cancel(h).is_err());
+    }
+
+    #[test]
+    fn reschedule_moves_the_deadline() {
+        let mut wheel = TimerWheel::new();
+        let h = wheel.periodic(at(0), ms(10), cb).unwrap();
+        wheel.reschedule(h, at(5), ms(20)).unwrap();
+        assert!(wheel.expire(at(10))[h.slot].is_none());
+        assert!(wheel.expire(at(25))[h.slot].is_some());
+        // the period is kept
+        assert!(wheel.expire(at(34))[h.slot].is_none());
+        assert!(wheel.expire(at(35))[h.slot].is_some());
+    }
+
+    #[test]
+    fn stale_handle_is_rejected() {
+        let mut wheel = TimerWheel::new();
+        let old = wheel.one_shot(at(0), ms(1), cb).unwrap();
+        wheel.expire(at(1));
+        // same slot, next generation
+        let new = wheel.one_shot(at(1), ms(10), cb).unwrap();
+        assert_eq!(old.slot, new.slot);
+        assert!(!wheel.is_active(old));
+        assert!(wheel.cancel(old).is_err());
+        assert!(wheel.reschedule(old, at(1), ms(1)).is_err());
+        assert!(wheel.is_active(new));
+    }
+
+    #[test]
+    fn slots_run_out() {
+        let mut wheel = TimerWheel::new();
+        for _ in 0..MAX_TIMERS {
+            wheel.one_shot(at(0), ms(1), cb).unwrap();
+        }
+        assert!(wheel.one_shot(at(0), ms(1), cb).is_err());
+    }
+
+    #[test]
+    fn deadline_across_tick_wrap() {
+        let mut wheel = TimerWheel::new();
+        let once = wheel.one_shot(at(0xFFFF_FFF0), ms(0x20), cb).unwrap();
+        let every = wheel.periodic(at(0xFFFF_FFF0), ms(0x10), cb).unwrap();
+        // not expired just before the wrap, even though the deadline is lower
+        assert!(wheel.expire(at(0xFFFF_FFFF))[once.slot].is_none());
+        assert!(wheel.expire(at(0))[every.slot].is_some());
+        assert!(wheel.expire(at(0x0F))[once.slot].is_none());
+        let expired = wheel.expire(at(0x10));
+        assert!(expired[once.slot].is_some());
+        assert!(expired[every.slot].is_some());
+    }
+}
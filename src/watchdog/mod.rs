@@ -0,0 +1,256 @@
+use stm32f103xx::{IWDG, WWDG, RCC, Interrupt};
+use cortex_m::peripheral::NVIC;
+use clocks::*;
+use reset::ResetCause;
+
+#[derive(Debug)]
+pub enum WatchdogError {
+    InvalidTimeout,
+    InvalidWindow,
+    // the prescaler or reload update wasn't acknowledged
+    UpdateTimeout,
+}
+
+type WatchdogResult<T> = Result<T, WatchdogError>;
+
+// typical lsi frequency, it can be anything from 30 to 60 khz so the iwdg
+// timeouts are only approximate
+pub const LSI_FREQ : u32 = 40_000;
+
+const KEY_START : u32 = 0xCCCC;
+const KEY_UNLOCK : u32 = 0x5555;
+const KEY_FEED : u32 = 0xAAAA;
+
+const IWDG_MAX_RELOAD : u32 = 0xFFF;
+const UPDATE_TIMEOUT : u32 = 100_000;
+
+const WWDG_T6 : u32 = 0x40;
+const WWDG_MAX_COUNTER : u32 = 0x7F;
+const WWDG_EWI : u32 = 1 << 9;
+const WWDG_WDGA : u32 = 1 << 7;
+
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum Watchdog {
+    Independent,
+    Window,
+}
+
+// watchdog which caused the last reset, the flags are kept until
+// ResetCause::clear
+pub fn last_reset() -> Option<Watchdog> {
+    let cause = ResetCause::read();
+    if cause.independent_watchdog {
+        Some(Watchdog::Independent)
+    } else if cause.window_watchdog {
+        Some(Watchdog::Window)
+    } else {
+        None
+    }
+}
+
+pub struct IwdgConfig {
+    timeout_ms : u32,
+}
+
+impl IwdgConfig {
+    pub fn new() -> IwdgConfig {
+        IwdgConfig {
+            timeout_ms : 1000,
+        }
+    }
+
+    // from 0.1 ms to about 26 s at 40 khz
+    pub fn timeout_ms(mut self, ms : u32) -> IwdgConfig {
+        self.timeout_ms = ms;
+        self
+    }
+
+    // the iwdg can't be stopped once started, except by a reset
+    pub fn start(&self) -> WatchdogResult<Iwdg> {
+        let (pr, rl) = iwdg_prescalers(LSI_FREQ, self.timeout_ms)?;
+        unsafe {
+            // the registers are updated in the lsi domain, so the lsi is
+            // started by hand and the watchdog only once they are set. on
+            // a timeout it isn't running and nothing needs feeding
+            (*RCC.get()).csr.modify(|_, w| w.lsion().bit(true));
+            let mut timeout = UPDATE_TIMEOUT;
+            while timeout > 0 && !(*RCC.get()).csr.read().lsirdy().bit() {
+                timeout -= 1;
+            }
+            if timeout == 0 {
+                return Err(WatchdogError::UpdateTimeout);
+            }
+            (*IWDG.get()).kr.write(|w| w.bits(KEY_UNLOCK));
+            (*IWDG.get()).pr.write(|w| w.bits(pr));
+            (*IWDG.get()).rlr.write(|w| w.bits(rl));
+            let mut timeout = UPDATE_TIMEOUT;
+            while timeout > 0 && (*IWDG.get()).sr.read().bits() & 0b11 != 0 {
+                timeout -= 1;
+            }
+            if timeout == 0 {
+                return Err(WatchdogError::UpdateTimeout);
+            }
+            (*IWDG.get()).kr.write(|w| w.bits(KEY_START));
+            (*IWDG.get()).kr.write(|w| w.bits(KEY_FEED));
+        }
+        Ok(Iwdg {
+            timeout_ms : iwdg_timeout_ms(LSI_FREQ, pr, rl),
+        })
+    }
+}
+
+pub struct Iwdg {
+    timeout_ms : u32,
+}
+
+impl Iwdg {
+    // actual timeout for the nominal lsi frequency
+    pub fn timeout_ms(&self) -> u32 {
+        self.timeout_ms
+    }
+
+    pub fn feed(&mut self) {
+        unsafe {
+            (*IWDG.get()).kr.write(|w| w.bits(KEY_FEED));
+        }
+    }
+}
+
+// PR code (divider 4 << pr) and RLR giving at least the timeout. the
+// smallest divider is used for the best resolution
+pub fn iwdg_prescalers(lsi : u32, ms : u32) -> WatchdogResult<(u32, u32)> {
+    if ms == 0 {
+        return Err(WatchdogError::InvalidTimeout);
+    }
+    for pr in 0..7 {
+        let div = 4 << pr;
+        // ticks of lsi / div in ms, rounded up
+        let ticks = (ms as u64 * lsi as u64 + div as u64 * 1000 - 1) / (div as u64 * 1000);
+        if ticks <= IWDG_MAX_RELOAD as u64 + 1 {
+            let ticks = if ticks == 0 { 1 } else { ticks as u32 };
+            return Ok((pr, ticks - 1));
+        }
+    }
+    Err(WatchdogError::InvalidTimeout)
+}
+
+fn iwdg_timeout_ms(lsi : u32, pr : u32, rl : u32) -> u32 {
+    ((rl as u64 + 1) * (4 << pr) as u64 * 1000 / lsi as u64) as u32
+}
+
+// early wakeup callback, called when the counter reaches 0x40, one tick
+// before the reset
+static mut EARLY_WAKEUP : Option<fn()> = None;
+
+pub struct WwdgConfig {
+    timeout_ms : u32,
+    window_ms : u32,
+    early_wakeup : Option<fn()>,
+}
+
+impl WwdgConfig {
+    pub fn new() -> WwdgConfig {
+        WwdgConfig {
+            timeout_ms : 50,
+            window_ms : 0,
+            early_wakeup : None,
+        }
+    }
+
+    // time from a feed to the reset, at most 64 * 4096 * 8 pclk1 periods
+    pub fn timeout_ms(mut self, ms : u32) -> WwdgConfig {
+        self.timeout_ms = ms;
+        self
+    }
+
+    // feeding earlier than this after the last feed also resets, 0 to
+    // allow feeding anytime
+    pub fn window_ms(mut self, ms : u32) -> WwdgConfig {
+        self.window_ms = ms;
+        self
+    }
+
+    // the callback may feed the watchdog, i.e. to save some state before
+    // letting it reset on the next wakeup
+    pub fn early_wakeup(mut self, cb : Option<fn()>) -> WwdgConfig {
+        self.early_wakeup = cb;
+        self
+    }
+
+    // the wwdg can't be stopped once started, except by a reset
+    pub fn start(&self) -> WatchdogResult<Wwdg> {
+        let pclk1 = ClockConfig::get_speeds().apb1_clk;
+        let (tb, counter, tick_us) = wwdg_prescalers(pclk1, self.timeout_ms)?;
+        let window = if self.window_ms == 0 {
+            WWDG_MAX_COUNTER
+        } else {
+            let ticks = (self.window_ms as u64 * 1000 + tick_us as u64 - 1) / tick_us as u64;
+            if ticks as u32 >= counter - WWDG_T6 {
+                return Err(WatchdogError::InvalidWindow);
+            }
+            counter - ticks as u32
+        };
+        unsafe {
+            (*RCC.get()).apb1enr.modify(|_, w| w.wwdgen().bit(true));
+            EARLY_WAKEUP = self.early_wakeup;
+            let ewi = if self.early_wakeup.is_some() { WWDG_EWI } else { 0 };
+            (*WWDG.get()).cfr.write(|w| w.bits(ewi | tb << 7 | window));
+            (*WWDG.get()).sr.write(|w| w.bits(0));
+            if self.early_wakeup.is_some() {
+                (*NVIC.get()).enable(Interrupt::WWDG);
+            }
+            (*WWDG.get()).cr.write(|w| w.bits(WWDG_WDGA | counter));
+        }
+        Ok(Wwdg {
+            counter,
+            window,
+        })
+    }
+}
+
+pub struct Wwdg {
+    counter : u32,
+    window : u32,
+}
+
+impl Wwdg {
+    // resets if done before the window opens
+    pub fn feed(&mut self) {
+        unsafe {
+            (*WWDG.get()).cr.write(|w| w.bits(WWDG_WDGA | self.counter));
+        }
+    }
+
+    // the window is open once the counter is down to the window value
+    pub fn can_feed(&self) -> bool {
+        let t = unsafe { (*WWDG.get()).cr.read().bits() & WWDG_MAX_COUNTER };
+        t <= self.window
+    }
+}
+
+// WDGTB, counter reload value and tick period in us. the counter counts
+// down from the reload to 0x40, the reset happens when it goes below
+pub fn wwdg_prescalers(pclk1 : u32, ms : u32) -> WatchdogResult<(u32, u32, u32)> {
+    if ms == 0 {
+        return Err(WatchdogError::InvalidTimeout);
+    }
+    for tb in 0..4 {
+        let div = 4096u64 << tb;
+        let ticks = (ms as u64 * pclk1 as u64 + div * 1000 - 1) / (div * 1000);
+        if ticks <= (WWDG_MAX_COUNTER - WWDG_T6 + 1) as u64 {
+            let ticks = if ticks == 0 { 1 } else { ticks as u32 };
+            let tick_us = (div * 1_000_000 / pclk1 as u64) as u32;
+            return Ok((tb, WWDG_T6 + ticks - 1, tick_us));
+        }
+    }
+    Err(WatchdogError::InvalidTimeout)
+}
+
+pub fn wwdg_handler() {
+    unsafe {
+        (*WWDG.get()).sr.write(|w| w.bits(0));
+        if let Some(cb) = EARLY_WAKEUP {
+            cb();
+        }
+    }
+}